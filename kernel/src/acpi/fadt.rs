@@ -0,0 +1,106 @@
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, AddressSpace, GenericAddress};
+
+// FADT flags
+const TMR_VAL_EXT: u32 = 1 << 8;
+const RESET_REG_SUP: u32 = 1 << 10;
+const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+// IA-PC boot architecture flags
+const LEGACY_DEVICES: u16 = 1 << 0;
+const HAS_8042: u16 = 1 << 1;
+const VGA_NOT_PRESENT: u16 = 1 << 2;
+const MSI_NOT_SUPPORTED: u16 = 1 << 3;
+const CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+/// Fixed ACPI Description Table: the fixed hardware and the legacy devices
+/// the firmware promises.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    /// ISA IRQ (or GSI on hardware reduced systems) of the SCI.
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    /// ACPI power management timer, 3.579545 MHz.
+    pub pm_timer: Option<GenericAddress>,
+    /// The PM timer counts 32 bits instead of 24.
+    pub pm_timer_32bit: bool,
+    /// CMOS RAM index of the RTC century register, 0 if there is none.
+    pub century_register: u8,
+    /// Register and value that reset the machine when written.
+    pub reset: Option<(GenericAddress, u8)>,
+    pub hardware_reduced: bool,
+    boot_arch: u16,
+}
+
+impl Fadt {
+    /// The board has ISA-style legacy devices (LPC/ISA bus).
+    pub fn has_legacy_devices(&self) -> bool {
+        self.boot_arch & LEGACY_DEVICES != 0
+    }
+
+    /// There is an 8042 keyboard controller.
+    pub fn has_8042(&self) -> bool {
+        self.boot_arch & HAS_8042 != 0
+    }
+
+    pub fn has_vga(&self) -> bool {
+        self.boot_arch & VGA_NOT_PRESENT == 0
+    }
+
+    pub fn supports_msi(&self) -> bool {
+        self.boot_arch & MSI_NOT_SUPPORTED == 0
+    }
+
+    pub fn has_cmos_rtc(&self) -> bool {
+        self.boot_arch & CMOS_RTC_NOT_PRESENT == 0
+    }
+}
+
+pub(super) fn parse(bytes: &[u8]) -> Result<Fadt, &'static str> {
+    // everything up to the flags has been there since ACPI 1.0
+    if bytes.len() < 116 {
+        return Err("FADT too short");
+    }
+    let len = bytes.len();
+    let flags = read_u32(bytes, 112);
+
+    let dsdt = if len >= 148 && read_u64(bytes, 140) != 0 {
+        read_u64(bytes, 140)
+    } else {
+        read_u32(bytes, 40) as u64
+    };
+
+    let extended_pm_timer = (len >= 220)
+        .then(|| GenericAddress::parse(&bytes[208..220]))
+        .filter(|gas| gas.address != 0);
+    let pm_timer = extended_pm_timer.or_else(|| {
+        let port = read_u32(bytes, 76);
+        (port != 0 && bytes[91] == 4).then_some(GenericAddress {
+            space: AddressSpace::SystemIo,
+            bit_width: 32,
+            bit_offset: 0,
+            access_size: 3,
+            address: port as u64,
+        })
+    });
+
+    let reset = (flags & RESET_REG_SUP != 0 && len >= 129)
+        .then(|| (GenericAddress::parse(&bytes[116..128]), bytes[128]));
+
+    Ok(Fadt {
+        revision: bytes[8],
+        dsdt: PhysAddr::try_new(dsdt).map_err(|_| "DSDT address out of range")?,
+        sci_interrupt: read_u16(bytes, 46),
+        smi_command_port: read_u32(bytes, 48),
+        pm_timer,
+        pm_timer_32bit: flags & TMR_VAL_EXT != 0,
+        century_register: bytes[108],
+        reset,
+        hardware_reduced: flags & HW_REDUCED_ACPI != 0,
+        // reserved in ACPI 1.0, where the answer is "everything a PC has"
+        boot_arch: if bytes[8] >= 2 { read_u16(bytes, 109) } else { LEGACY_DEVICES | HAS_8042 },
+    })
+}
@@ -0,0 +1,43 @@
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, GenericAddress};
+
+/// HPET description table: where the event timer block lives and what it
+/// can do.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub address: PhysAddr,
+    /// Sequence number of this timer block, for boards with more than one.
+    pub number: u8,
+    pub hardware_revision: u8,
+    /// Comparators in the first timer block.
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    /// Can take over the PIT and RTC interrupt lines.
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    /// Smallest tick, in main counter ticks, periodic mode can use without
+    /// losing interrupts.
+    pub minimum_tick: u16,
+}
+
+pub(super) fn parse(bytes: &[u8]) -> Result<Hpet, &'static str> {
+    if bytes.len() < 56 {
+        return Err("HPET table too short");
+    }
+    let id = read_u32(bytes, 36);
+    let base = GenericAddress::parse(&bytes[40..52]);
+    if !base.is_memory() {
+        return Err("HPET is not memory mapped");
+    }
+    Ok(Hpet {
+        address: PhysAddr::try_new(base.address).map_err(|_| "HPET address out of range")?,
+        number: bytes[52],
+        hardware_revision: id as u8,
+        comparators: ((id >> 8) & 0x1F) as u8 + 1,
+        counter_64bit: id & (1 << 13) != 0,
+        legacy_replacement: id & (1 << 15) != 0,
+        vendor_id: (id >> 16) as u16,
+        minimum_tick: read_u16(bytes, 53),
+    })
+}
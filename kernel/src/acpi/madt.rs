@@ -0,0 +1,151 @@
+use alloc::vec::Vec;
+
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, SDT_HEADER_SIZE};
+
+// MADT flags
+const PCAT_COMPAT: u32 = 1 << 0;
+
+// local APIC flags
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A processor's local APIC as listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicInfo {
+    /// ACPI processor UID, matching the `_UID` of the processor object.
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Usable now.
+    pub enabled: bool,
+    /// Disabled, but can be brought online later (hotplug).
+    pub online_capable: bool,
+}
+
+/// An I/O APIC as listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// Interrupt input polarity from the MADT `MPS INTI` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Interrupt trigger mode from the MADT `MPS INTI` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// An ISA IRQ that is not wired to the GSI with the same number, or not
+/// with the ISA default of active high, edge triggered.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Multiple APIC Description Table: the interrupt controllers in the system.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The board also has a pair of 8259 PICs that must be masked.
+    pub pcat_compat: bool,
+    pub local_apics: Vec<LocalApicInfo>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Local APICs of the processors that can run code right now.
+    pub fn enabled_cpus(&self) -> impl Iterator<Item = &LocalApicInfo> {
+        self.local_apics.iter().filter(|lapic| lapic.enabled)
+    }
+}
+
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    // 0b00 means "conforms to the bus", which for ISA is active high, edge
+    let polarity = if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh };
+    let trigger = if (flags >> 2) & 0b11 == 0b11 { TriggerMode::Level } else { TriggerMode::Edge };
+    (polarity, trigger)
+}
+
+pub(super) fn parse(bytes: &[u8]) -> Result<Madt, &'static str> {
+    if bytes.len() < SDT_HEADER_SIZE + 8 {
+        return Err("MADT too short");
+    }
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(bytes, 36) as u64),
+        pcat_compat: read_u32(bytes, 40) & PCAT_COMPAT != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= bytes.len() {
+        let (kind, len) = (bytes[offset], bytes[offset + 1] as usize);
+        if len < 2 || offset + len > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + len];
+        match kind {
+            // processor local APIC
+            0 if len >= 8 => {
+                let flags = read_u32(entry, 4);
+                madt.local_apics.push(LocalApicInfo {
+                    processor_uid: entry[2] as u32,
+                    apic_id: entry[3] as u32,
+                    enabled: flags & LAPIC_ENABLED != 0,
+                    online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                });
+            }
+            1 if len >= 12 => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                // 32 bits wide, so always a valid physical address
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 if len >= 10 => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                madt.overrides.push(InterruptOverride {
+                    isa_irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    polarity,
+                    trigger,
+                });
+            }
+            // local APIC address override; one past the physical address
+            // width is ignored and the 32-bit address kept
+            5 if len >= 12 => {
+                if let Ok(address) = PhysAddr::try_new(read_u64(entry, 4)) {
+                    madt.local_apic_address = address;
+                }
+            }
+            // processor local x2APIC, for IDs that do not fit a byte
+            9 if len >= 16 => {
+                let flags = read_u32(entry, 8);
+                madt.local_apics.push(LocalApicInfo {
+                    processor_uid: read_u32(entry, 12),
+                    apic_id: read_u32(entry, 4),
+                    enabled: flags & LAPIC_ENABLED != 0,
+                    online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                });
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(madt)
+}
@@ -0,0 +1,47 @@
+use alloc::vec::Vec;
+
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u64, SDT_HEADER_SIZE};
+
+const ENTRY_SIZE: usize = 16;
+
+/// One PCI segment group's memory mapped (ECAM) configuration space.
+#[derive(Debug, Clone, Copy)]
+pub struct PciSegment {
+    /// Address of bus 0 of the segment, even if `bus_start` is higher.
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+impl PciSegment {
+    /// Physical address of the 4 KiB configuration space of a function, if
+    /// the bus is decoded by this segment.
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if !(self.bus_start..=self.bus_end).contains(&bus) || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus as u64) << 20) | ((device as u64) << 15) | ((function as u64) << 12);
+        Some(self.base + offset)
+    }
+}
+
+pub(super) fn parse(bytes: &[u8]) -> Vec<PciSegment> {
+    // 8 reserved bytes follow the header
+    bytes
+        .get(SDT_HEADER_SIZE + 8..)
+        .unwrap_or_default()
+        .chunks_exact(ENTRY_SIZE)
+        // a base past the physical address width is firmware garbage
+        .filter_map(|entry| {
+            Some(PciSegment {
+                base: PhysAddr::try_new(read_u64(entry, 0)).ok()?,
+                segment: read_u16(entry, 8),
+                bus_start: entry[10],
+                bus_end: entry[11],
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,282 @@
+mod fadt;
+mod hpet;
+mod madt;
+mod mcfg;
+
+use alloc::vec::Vec;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::boot::rsdp_address;
+use crate::memory::mmio::{map_mmio, unmap_mmio, CacheType, MmioRegion};
+use crate::serial_log;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::{InterruptOverride, IoApicInfo, LocalApicInfo, Madt, Polarity, TriggerMode};
+pub use mcfg::PciSegment;
+
+const SDT_HEADER_SIZE: usize = 36;
+
+// RSDP layout
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// Address space a [`GenericAddress`] points into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// ACPI Generic Address Structure: a register in memory, I/O or PCI space.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 0 undefined, 1 byte, 2 word, 3 dword, 4 qword.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        self.space == AddressSpace::SystemMemory
+    }
+}
+
+/// Everything the kernel took from the ACPI tables at boot.
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// PCI Express configuration space, one entry per segment group.
+    pub pci_segments: Vec<PciSegment>,
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+
+/// A mapped ACPI table, unmapped again on drop.
+struct Table {
+    region: Option<MmioRegion>,
+}
+
+impl Table {
+    /// Map the table whose header is at `phys`, using the length it declares,
+    /// and check its checksum.
+    fn map(phys: PhysAddr) -> Result<Self, &'static str> {
+        let header = unsafe { map_mmio(phys, SDT_HEADER_SIZE, CacheType::WriteBack)? };
+        let length = read_u32(unsafe { region_bytes(&header) }, 4) as usize;
+        unsafe { unmap_mmio(header) };
+        if length < SDT_HEADER_SIZE {
+            return Err("ACPI table shorter than its header");
+        }
+        let region = unsafe { map_mmio(phys, length, CacheType::WriteBack)? };
+        let table = Self { region: Some(region) };
+        if !checksum_ok(table.bytes()) {
+            return Err("bad ACPI table checksum");
+        }
+        Ok(table)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { region_bytes(self.region.as_ref().unwrap()) }
+    }
+
+    fn signature(&self) -> &[u8] {
+        &self.bytes()[0..4]
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Some(region) = self.region.take() {
+            unsafe { unmap_mmio(region) };
+        }
+    }
+}
+
+/// # Safety
+/// The region must map ordinary memory that nothing writes concurrently.
+unsafe fn region_bytes(region: &MmioRegion) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(region.as_mut_ptr::<u8>(), region.len()) }
+}
+
+/// ACPI structures are valid when all their bytes sum to zero.
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// What the RSDP says: ACPI revision, OEM and the root table to read.
+struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    root: PhysAddr,
+    /// Root table entries are 64-bit (XSDT) rather than 32-bit (RSDT).
+    extended: bool,
+}
+
+fn parse_rsdp(phys: PhysAddr) -> Result<Rsdp, &'static str> {
+    // copy it out; a revision 1 RSDP is only 20 bytes but reading past it is harmless
+    let region = unsafe { map_mmio(phys, RSDP_V2_SIZE, CacheType::WriteBack)? };
+    let bytes: [u8; RSDP_V2_SIZE] = unsafe { region_bytes(&region) }.try_into().unwrap();
+    unsafe { unmap_mmio(region) };
+
+    if &bytes[0..8] != b"RSD PTR " {
+        return Err("bad RSDP signature");
+    }
+    if !checksum_ok(&bytes[..RSDP_V1_SIZE]) {
+        return Err("bad RSDP checksum");
+    }
+    let revision = bytes[15];
+    let oem_id = bytes[9..15].try_into().unwrap();
+
+    // the XSDT is preferred whenever the firmware gives one
+    if revision >= 2 && read_u64(&bytes, 24) != 0 {
+        if (read_u32(&bytes, 20) as usize) < RSDP_V2_SIZE || !checksum_ok(&bytes) {
+            return Err("bad extended RSDP checksum");
+        }
+        let root = PhysAddr::try_new(read_u64(&bytes, 24)).map_err(|_| "XSDT address out of range")?;
+        return Ok(Rsdp { revision, oem_id, root, extended: true });
+    }
+    let root = PhysAddr::new(read_u32(&bytes, 16) as u64);
+    Ok(Rsdp { revision, oem_id, root, extended: false })
+}
+
+/// Physical addresses of every table the RSDT or XSDT points to.
+fn root_table_entries(rsdp: &Rsdp) -> Result<Vec<PhysAddr>, &'static str> {
+    let root = Table::map(rsdp.root)?;
+    let expected: &[u8] = if rsdp.extended { b"XSDT" } else { b"RSDT" };
+    if root.signature() != expected {
+        return Err("root table has the wrong signature");
+    }
+    let entry_size = if rsdp.extended { 8 } else { 4 };
+    // entries past the physical address width are skipped
+    Ok(root.bytes()[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .filter_map(|entry| match entry_size {
+            8 => PhysAddr::try_new(read_u64(entry, 0)).ok(),
+            _ => Some(PhysAddr::new(read_u32(entry, 0) as u64)),
+        })
+        .collect())
+}
+
+/// Find and parse the ACPI tables the kernel uses. Must run on the BSP before
+/// bootloader memory is reclaimed; missing or corrupt tables are reported,
+/// not fatal.
+pub fn init() {
+    let Some(rsdp) = rsdp_address() else {
+        serial_log!("ACPI: no RSDP from the bootloader");
+        return;
+    };
+    let rsdp = match parse_rsdp(rsdp) {
+        Ok(rsdp) => rsdp,
+        Err(e) => {
+            serial_log!("ACPI: {}", e);
+            return;
+        }
+    };
+    let entries = match root_table_entries(&rsdp) {
+        Ok(entries) => entries,
+        Err(e) => {
+            serial_log!("ACPI: {}", e);
+            return;
+        }
+    };
+
+    let mut tables = AcpiTables {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        pci_segments: Vec::new(),
+    };
+    for phys in entries {
+        let table = match Table::map(phys) {
+            Ok(table) => table,
+            Err(e) => {
+                serial_log!("ACPI: table at {:?}: {}", phys, e);
+                continue;
+            }
+        };
+        let bytes = table.bytes();
+        match table.signature() {
+            b"APIC" => tables.madt = madt::parse(bytes).inspect_err(|e| { serial_log!("ACPI: {}", e); }).ok(),
+            b"FACP" => tables.fadt = fadt::parse(bytes).inspect_err(|e| { serial_log!("ACPI: {}", e); }).ok(),
+            b"HPET" => tables.hpet = hpet::parse(bytes).inspect_err(|e| { serial_log!("ACPI: {}", e); }).ok(),
+            b"MCFG" => tables.pci_segments = mcfg::parse(bytes),
+            _ => {}
+        }
+    }
+
+    serial_log!(
+        "ACPI: revision {}, OEM {}, MADT {}, FADT {}, HPET {}, {} PCI segment(s)",
+        tables.revision,
+        core::str::from_utf8(&tables.oem_id).unwrap_or("?").trim_end(),
+        tables.madt.is_some(),
+        tables.fadt.is_some(),
+        tables.hpet.is_some(),
+        tables.pci_segments.len()
+    );
+    TABLES.call_once(|| tables);
+}
+
+/// All parsed tables, if the firmware had a usable RSDP.
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    tables()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    tables()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    tables()?.hpet.as_ref()
+}
+
+/// The ECAM segment that decodes `bus` in segment group `segment`.
+pub fn pci_segment(segment: u16, bus: u8) -> Option<&'static PciSegment> {
+    tables()?
+        .pci_segments
+        .iter()
+        .find(|s| s.segment == segment && (s.bus_start..=s.bus_end).contains(&bus))
+}
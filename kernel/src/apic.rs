@@ -1,158 +1,256 @@
-use x86_64::instructions::port::Port;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use spin::Once;
 use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::exception::SPURIOUS_VECTOR;
+use crate::memory::mmio::{map_mmio, CacheType, MmioRegion};
+use crate::serial_log;
+use crate::time::{self, tsc, Instant};
 
 // APIC Timer Vector (interrupt number)
 pub const APIC_TIMER_VECTOR: u8 = 32;
 
 // IA32_APIC_BASE MSR
 const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
 const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// CPUID leaf 1 feature bits
+const CPUID_EDX_APIC: u32 = 1 << 9;
+const CPUID_ECX_X2APIC: u32 = 1 << 21;
+const CPUID_ECX_TSC_DEADLINE: u32 = 1 << 24;
+
+// Absolute TSC value the timer fires at in TSC-deadline mode; 0 disarms it
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
 
-// xAPIC MMIO offsets
-const XAPIC_ID: usize = 0x020;
-const XAPIC_EOI: usize = 0x0B0;
-const XAPIC_SVR: usize = 0x0F0;
-const XAPIC_LVT_TIMER: usize = 0x320;
-const XAPIC_TIMER_INIT: usize = 0x380;
-const XAPIC_TIMER_CURRENT: usize = 0x390;
-const XAPIC_TIMER_DIV: usize = 0x3E0;
+// Register offsets in the xAPIC MMIO page. In x2APIC mode the same register
+// is MSR 0x800 + offset / 16.
+const APIC_ID: usize = 0x020;
+const APIC_EOI: usize = 0x0B0;
+const APIC_SVR: usize = 0x0F0;
+const APIC_ICR_LOW: usize = 0x300;
+const APIC_ICR_HIGH: usize = 0x310;
+const APIC_LVT_TIMER: usize = 0x320;
+const APIC_TIMER_INIT: usize = 0x380;
+const APIC_TIMER_CURRENT: usize = 0x390;
+const APIC_TIMER_DIV: usize = 0x3E0;
 
-// Local APIC base address
-const APIC_BASE_ADDRESS: usize = 0xFEE0_0000;
+const X2APIC_MSR_BASE: u32 = 0x800;
+const X2APIC_ICR: u32 = 0x830;
+
+// Interrupt command register fields
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DEST_ALL: u32 = 0b10 << 18;
+const ICR_DEST_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+// Local APIC register page, mapped uncached by `map_local_apic`.
+// Every core sees its own APIC at the same physical address, so one mapping serves all.
+static APIC_MMIO: Once<MmioRegion> = Once::new();
+const XAPIC_MMIO_SIZE: usize = 0x1000;
+
+// Whether every core runs its APIC in x2APIC mode; decided once on the BSP.
+static X2APIC: AtomicBool = AtomicBool::new(false);
 
 // Timer modes
-const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_ONE_SHOT: u32 = 0b00 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
 const TIMER_MASKED: u32 = 1 << 16;
 
-// MMIO access functions for xAPIC mode
+// Timer ticks per second at divide-by-16, measured once on the BSP
+static TIMER_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+// Whether the timers run in TSC-deadline rather than one-shot mode
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// The CPU can run its local APIC in x2APIC (MSR) mode.
+pub fn x2apic_supported() -> bool {
+    unsafe { __cpuid(1) }.ecx & CPUID_ECX_X2APIC != 0
+}
+
+/// The local APICs are driven through MSRs rather than MMIO.
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+// Register access for whichever mode is in use
 #[inline]
-unsafe fn xapic_read(offset: usize) -> u32 {
-    unsafe {
-        let ptr = (APIC_BASE_ADDRESS + offset) as *const u32;
-        ptr.read_volatile()
+unsafe fn apic_read(offset: usize) -> u32 {
+    if is_x2apic() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).read() as u32 }
+    } else {
+        APIC_MMIO.get().expect("local APIC not mapped").read(offset)
     }
 }
 
 #[inline]
-unsafe fn xapic_write(offset: usize, value: u32) {
-    unsafe {
-        let ptr = (APIC_BASE_ADDRESS + offset) as *mut u32;
-        ptr.write_volatile(value);
+unsafe fn apic_write(offset: usize, value: u32) {
+    if is_x2apic() {
+        unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32).write(value as u64) }
+    } else {
+        APIC_MMIO.get().expect("local APIC not mapped").write(offset, value);
     }
 }
 
-/// Disable the legacy PIC timer only (keep keyboard working)
-pub fn disable_pic_timer() {
-    unsafe {
-        let mut pic1_data = Port::<u8>::new(0x21);
-
-        // Mask only the timer interrupt (IRQ0) on master PIC
-        // Keep keyboard (IRQ1) and other interrupts unmasked
-        let current_mask = pic1_data.read();
-        pic1_data.write(current_mask | 0x01); // Mask IRQ0 (timer) only
+/// Pick the APIC mode every core will use: x2APIC when the CPU has it (or
+/// the firmware already switched to it, which cannot be undone without
+/// disabling the APIC), otherwise xAPIC, whose register page (at the address
+/// IA32_APIC_BASE reports) gets mapped into the MMIO window. Must run on the
+/// BSP before `init_apic`.
+pub fn map_local_apic() -> Result<(), &'static str> {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if x2apic_supported() || apic_base & APIC_BASE_X2APIC != 0 {
+        X2APIC.store(true, Ordering::Relaxed);
+        serial_log!("APIC: x2APIC mode");
+        return Ok(());
     }
+
+    let base = apic_base & APIC_BASE_ADDR_MASK;
+    let region = unsafe { map_mmio(PhysAddr::new(base), XAPIC_MMIO_SIZE, CacheType::Uncached)? };
+    APIC_MMIO.call_once(|| region);
+    serial_log!("APIC: xAPIC mode at {:#x}", base);
+    Ok(())
 }
 
-/// Initialize APIC on current core
+/// Initialize APIC on current core, in the mode `map_local_apic` chose
 pub fn init_apic() -> Result<(), &'static str> {
+    if unsafe { __cpuid(1) }.edx & CPUID_EDX_APIC == 0 {
+        return Err("APIC not available on this CPU");
+    }
+
     unsafe {
         let mut apic_base_msr = Msr::new(IA32_APIC_BASE);
         let mut apic_base = apic_base_msr.read();
 
-        // Check if APIC is available
-        if apic_base & APIC_BASE_ENABLE == 0 {
-            return Err("APIC not available on this CPU");
-        }
-
         // Enable APIC if not already enabled
         if apic_base & APIC_BASE_ENABLE == 0 {
             apic_base |= APIC_BASE_ENABLE;
             apic_base_msr.write(apic_base);
         }
 
-        // Set Spurious Interrupt Vector Register using MMIO
+        // x2APIC can only be entered from an enabled xAPIC, hence the second write
+        if is_x2apic() && apic_base & APIC_BASE_X2APIC == 0 {
+            apic_base |= APIC_BASE_X2APIC;
+            apic_base_msr.write(apic_base);
+        }
+
+        // Set Spurious Interrupt Vector Register
         // Bit 8: APIC Software Enable/Disable
         // Bits 0-7: Spurious Vector
-        xapic_write(XAPIC_SVR, 0x1FF); // Vector 0xFF + enable bit
+        apic_write(APIC_SVR, 0x100 | SPURIOUS_VECTOR as u32);
     }
 
     Ok(())
 }
 
-/// Calibrate APIC timer using PIT for accurate timing
+/// Calibrate APIC timer against the HPET (or PIT channel 2); returns timer
+/// ticks per second at the divide-by-16 setting `init_apic_timer` uses
 pub fn calibrate_apic_timer() -> u32 {
     unsafe {
         // Set APIC timer to maximum count for calibration
-        xapic_write(XAPIC_TIMER_DIV, 0x3); // Divide by 16
-        xapic_write(XAPIC_LVT_TIMER, TIMER_MASKED | (APIC_TIMER_VECTOR as u32));
-        xapic_write(XAPIC_TIMER_INIT, 0xFFFFFFFF); // Maximum count
-
-        // Configure PIT for 10ms delay (100Hz)
-        // PIT channel 0, mode 2 (rate generator), binary count
-        let mut pit_cmd = Port::<u8>::new(0x43);
-        let mut pit_data = Port::<u8>::new(0x40);
-
-        pit_cmd.write(0x34); // Channel 0, lobyte/hibyte, mode 2
-        pit_data.write(0x9B); // 0x9B27 = 1193 for 10ms at 1.19318MHz
-        pit_data.write(0x2E);
-
-        // Wait for PIT tick
-        let mut current_count = 0xFFFFFFFF;
-        for _ in 0..10000 {
-            // Wait loop with timeout
-            current_count = xapic_read(XAPIC_TIMER_CURRENT);
-            if current_count < 0xFFFFFFFF {
-                break;
-            }
-        }
-
-        // Stop APIC timer
-        xapic_write(XAPIC_TIMER_INIT, 0);
+        apic_write(APIC_TIMER_DIV, 0x3); // Divide by 16
+        apic_write(APIC_LVT_TIMER, TIMER_MASKED | (APIC_TIMER_VECTOR as u32));
+        apic_write(APIC_TIMER_INIT, 0xFFFFFFFF); // Maximum count
+    }
 
-        // Calculate ticks per 10ms
-        let ticks_10ms = 0xFFFFFFFF - current_count;
+    // the timer counts down, so count how far it has fallen
+    let ticks_per_second =
+        time::measure_frequency(|| (u32::MAX - unsafe { apic_read(APIC_TIMER_CURRENT) }) as u64);
 
-        // Calculate ticks per second: ticks_10ms * 100
-        let ticks_per_second = ticks_10ms * 100;
+    // Stop APIC timer
+    unsafe { apic_write(APIC_TIMER_INIT, 0) };
 
-        ticks_per_second
-    }
+    ticks_per_second as u32
 }
 
-/// Initialize APIC timer on current core
+/// Initialize APIC timer on current core, unarmed, in TSC-deadline mode if
+/// the CPU has it and the TSC is invariant, one-shot mode otherwise. The
+/// first call (on the BSP) calibrates it.
 pub fn init_apic_timer() -> Result<(), &'static str> {
-    unsafe {
-        // Calibrate APIC timer first
+    if TIMER_FREQUENCY.load(Ordering::Relaxed) == 0 {
         let ticks_per_second = calibrate_apic_timer();
+        if ticks_per_second < 1000 {
+            return Err("APIC timer calibration failed");
+        }
+        TIMER_FREQUENCY.store(ticks_per_second, Ordering::Relaxed);
 
-        // Calculate initial count for 100Hz
-        let initial_count = ticks_per_second;
+        let tsc_deadline = unsafe { __cpuid(1) }.ecx & CPUID_ECX_TSC_DEADLINE != 0;
+        TSC_DEADLINE.store(tsc_deadline && tsc::is_invariant(), Ordering::Relaxed);
+        serial_log!(
+            "APIC timer: {} Hz, {} mode",
+            ticks_per_second,
+            if uses_tsc_deadline() { "TSC-deadline" } else { "one-shot" }
+        );
+    }
 
+    unsafe {
         // Set divide configuration (divide by 16)
-        xapic_write(XAPIC_TIMER_DIV, 0x3); // Divide by 16
+        apic_write(APIC_TIMER_DIV, 0x3); // Divide by 16
 
-        // Set initial count for 100Hz
-        xapic_write(XAPIC_TIMER_INIT, initial_count as u32);
+        // Nothing armed yet
+        apic_write(APIC_TIMER_INIT, 0);
 
-        // Enable timer in periodic mode
-        xapic_write(XAPIC_LVT_TIMER, TIMER_PERIODIC | (APIC_TIMER_VECTOR as u32));
+        let mode = if uses_tsc_deadline() { TIMER_TSC_DEADLINE } else { TIMER_ONE_SHOT };
+        apic_write(APIC_LVT_TIMER, mode | (APIC_TIMER_VECTOR as u32));
     }
 
     Ok(())
 }
 
+/// Timer ticks per second, 0 before the timer is calibrated
+pub fn timer_frequency() -> u32 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// The timer is programmed with absolute TSC deadlines
+pub fn uses_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Fire this core's timer interrupt once at `deadline`, replacing whatever
+/// was armed. A one-shot count too large for the register fires early; the
+/// caller re-arms then.
+pub fn arm_timer(deadline: Instant) {
+    let delay = deadline.duration_since(Instant::now()).as_nanos();
+    if uses_tsc_deadline() {
+        let ticks = delay * tsc::frequency() as u128 / 1_000_000_000;
+        let target = tsc::read().saturating_add(ticks.min(u64::MAX as u128) as u64);
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(target.max(1)) };
+    } else {
+        let ticks = delay * timer_frequency() as u128 / 1_000_000_000;
+        unsafe { apic_write(APIC_TIMER_INIT, ticks.clamp(1, u32::MAX as u128) as u32) };
+    }
+}
+
+/// Cancel whatever this core's timer has armed
+pub fn disarm_timer() {
+    if uses_tsc_deadline() {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+    } else {
+        unsafe { apic_write(APIC_TIMER_INIT, 0) };
+    }
+}
+
 /// Send End of Interrupt
 #[inline]
 pub fn end_of_interrupt() {
     unsafe {
-        // Use xAPIC MMIO
-        xapic_write(XAPIC_EOI, 0);
+        apic_write(APIC_EOI, 0);
     }
 }
 
-/// Get current APIC ID
+/// Get current APIC ID: all 32 bits in x2APIC mode, the top byte of the
+/// ID register in xAPIC mode
 pub fn get_apic_id() -> u32 {
-    unsafe { xapic_read(XAPIC_ID) }
+    let id = unsafe { apic_read(APIC_ID) };
+    if is_x2apic() { id } else { id >> 24 }
 }
 
 /// Check if APIC is initialized
@@ -163,3 +261,66 @@ pub fn is_apic_initialized() -> bool {
         (apic_base & APIC_BASE_ENABLE) != 0
     }
 }
+
+/// Who an inter-processor interrupt goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// The CPU with this local APIC ID.
+    Apic(u32),
+    /// Every CPU, including the sender.
+    All,
+    /// Every CPU except the sender.
+    AllButSelf,
+}
+
+/// What an inter-processor interrupt does on arrival.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    /// An ordinary interrupt on this vector.
+    Fixed(u8),
+    /// A non-maskable interrupt, delivered even with interrupts disabled.
+    /// The target runs the handler from `exception::register_nmi_handler`.
+    Nmi,
+    /// Reset the target into wait-for-SIPI; the only way to stop a core
+    /// that ignores everything else.
+    Init,
+}
+
+/// Send an inter-processor interrupt through this core's local APIC.
+pub fn send_ipi(destination: IpiDestination, kind: IpiKind) -> Result<(), &'static str> {
+    let mut low = match kind {
+        IpiKind::Fixed(vector) => ICR_DELIVERY_FIXED | vector as u32,
+        IpiKind::Nmi => ICR_DELIVERY_NMI,
+        IpiKind::Init => ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT,
+    };
+    let target = match destination {
+        IpiDestination::Apic(id) => {
+            if !is_x2apic() && id > 0xFF {
+                return Err("APIC ID does not fit an xAPIC destination");
+            }
+            id
+        }
+        IpiDestination::All => {
+            low |= ICR_DEST_ALL;
+            0
+        }
+        IpiDestination::AllButSelf => {
+            low |= ICR_DEST_ALL_BUT_SELF;
+            0
+        }
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if is_x2apic() {
+            // one 64-bit write, and no send pending bit to wait on
+            Msr::new(X2APIC_ICR).write(((target as u64) << 32) | low as u64);
+        } else {
+            apic_write(APIC_ICR_HIGH, target << 24);
+            apic_write(APIC_ICR_LOW, low);
+            while apic_read(APIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    });
+    Ok(())
+}
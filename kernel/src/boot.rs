@@ -1,12 +1,16 @@
-use limine::framebuffer::Framebuffer;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use limine::memory_map::{Entry, EntryType};
 use limine::mp::Cpu;
 use limine::response::MemoryMapResponse;
 use limine::BaseRevision;
-use limine::request::{FramebufferRequest, HhdmRequest, MemoryMapRequest, MpRequest, RequestsEndMarker, RequestsStartMarker};
+use limine::request::{ExecutableAddressRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, MpRequest, RequestsEndMarker, RequestsStartMarker, RsdpRequest};
 use spin::Once;
-use crate::gdt::init_gdt;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::gdt::{init_gdt, kernel_stack_top, switch_stack, wait_for_aps};
 use crate::memory::heap::init_heap;
-use crate::memory::{self, BootInfoFrameAllocator};
+use crate::memory::{self, frame, hhdm_to_phys};
+use crate::memory::paging::KernelAddress;
 
 #[cfg(feature = "qemu_test")]
 use crate::lib_main;
@@ -39,6 +43,14 @@ static MP_REQUEST: MpRequest = MpRequest::new();
 #[unsafe(link_section = ".requests")]
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
 /// Define the start and end markers for Limine requests.
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
@@ -47,14 +59,79 @@ static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
 #[unsafe(link_section = ".requests_end_marker")]
 static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
+/// Framebuffer geometry copied out of Limine's response.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub phys_addr: PhysAddr,
+    pub width: u64,
+    pub height: u64,
+    pub pitch: u64,
+    pub bpp: u16,
+}
+
+/// Logical number of the bootstrap processor; the APs are numbered from 1.
+pub const BSP_INDEX: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    pub processor_id: u32,
+    pub lapic_id: u32,
+}
+
+/// Everything the kernel needs from the bootloader, copied into kernel-owned
+/// memory so the bootloader-reclaimable regions can be handed to the frame
+/// allocator.
 pub struct BootInfo {
-    pub framebuffer: Framebuffer<'static>,
-    pub memory_map: &'static MemoryMapResponse,
-    pub cpus: &'static [&'static Cpu],
+    pub framebuffer: FramebufferInfo,
+    pub memory_map: Vec<Entry>,
+    /// Indexed by logical CPU number: the BSP at [`BSP_INDEX`], then the APs
+    /// by ascending local APIC ID.
+    pub cpus: Vec<CpuInfo>,
+}
+
+impl BootInfo {
+    pub fn bsp(&self) -> &CpuInfo {
+        &self.cpus[BSP_INDEX]
+    }
+
+    /// Logical number of the CPU with local APIC ID `lapic_id`.
+    pub fn cpu_index(&self, lapic_id: u32) -> Option<usize> {
+        self.cpus.iter().position(|cpu| cpu.lapic_id == lapic_id)
+    }
+
+    pub fn ap_count(&self) -> usize {
+        self.cpus.len() - 1
+    }
+
+    /// Position of logical CPU `cpu_index` among the APs, `None` for the
+    /// BSP or an unknown CPU.
+    pub fn ap_index(&self, cpu_index: usize) -> Option<usize> {
+        (cpu_index != BSP_INDEX && cpu_index < self.cpus.len()).then(|| cpu_index - 1)
+    }
+}
+
+/// Number the CPUs Limine found. Limine lists them in no promised order, so
+/// the BSP goes first by its local APIC ID and the APs follow sorted by
+/// theirs, which keeps the numbering the same from boot to boot.
+fn logical_cpus(cpus: &[&Cpu], bsp_lapic_id: u32) -> Vec<CpuInfo> {
+    let mut cpus: Vec<CpuInfo> = cpus
+        .iter()
+        .map(|c| CpuInfo { processor_id: c.id, lapic_id: c.lapic_id })
+        .collect();
+    cpus.sort_by_key(|cpu| (cpu.lapic_id != bsp_lapic_id, cpu.lapic_id));
+    assert!(
+        cpus.first().is_some_and(|cpu| cpu.lapic_id == bsp_lapic_id),
+        "BSP missing from the MP response"
+    );
+    cpus
 }
 
 static BOOT_INFO: Once<BootInfo> = Once::new();
 
+/// Set once bootloader-reclaimable memory has been given to the frame allocator;
+/// Limine's responses must not be touched after that.
+static RECLAIMED: AtomicBool = AtomicBool::new(false);
+
 pub fn boot_info() -> &'static BootInfo {
     unsafe { BOOT_INFO.get().unwrap_unchecked() }
 }
@@ -83,9 +160,22 @@ unsafe extern "C" fn kmain() -> ! {
 
 
     let memory_map = MEMORY_MAP_REQUEST.get_response().expect("No memory map available");
-    let cpus = MP_REQUEST.get_response().expect("No MP response from Limine").cpus();
+    let cpus = limine_cpus();
+
+    init_memory(memory_map);
 
-    let boot_info = BootInfo { framebuffer,memory_map,cpus };
+    // copy what we need while Limine's responses are still valid
+    let boot_info = BootInfo {
+        framebuffer: FramebufferInfo {
+            phys_addr: hhdm_to_phys(VirtAddr::from_ptr(framebuffer.addr())),
+            width: framebuffer.width(),
+            height: framebuffer.height(),
+            pitch: framebuffer.pitch(),
+            bpp: framebuffer.bpp(),
+        },
+        memory_map: memory_map.entries().iter().map(|e| **e).collect(),
+        cpus: logical_cpus(cpus, bsp_lapic_id()),
+    };
     BOOT_INFO.call_once(|| boot_info);
 
     init();
@@ -105,6 +195,16 @@ unsafe extern "C" fn kmain() -> ! {
     //     );
     // }
 
+    // leave Limine's stack for the BSP's guarded kernel stack
+    unsafe { switch_stack(kernel_stack_top(BSP_INDEX), BSP_INDEX, kmain_on_kernel_stack) }
+}
+
+extern "C" fn kmain_on_kernel_stack(_core_index: usize) -> ! {
+    // APs read their Cpu entries until they are on their own stacks
+    wait_for_aps();
+    reclaim_bootloader_memory();
+    memory::print_memory_map(&boot_info().memory_map);
+
     #[cfg(feature = "qemu_test")]
     lib_main();
 
@@ -112,22 +212,77 @@ unsafe extern "C" fn kmain() -> ! {
     unsafe { kernel_main(); } 
 }
 
-pub fn init() {
-    use x86_64::VirtAddr;
+/// Limine's CPU entries. Only valid until bootloader memory is reclaimed.
+pub fn limine_cpus() -> &'static [&'static Cpu] {
+    assert!(!RECLAIMED.load(Ordering::SeqCst), "Limine MP response used after reclaim");
+    MP_REQUEST.get_response().expect("No MP response from Limine").cpus()
+}
+
+/// Local APIC ID of the CPU Limine handed control to. Only valid until
+/// bootloader memory is reclaimed.
+pub fn bsp_lapic_id() -> u32 {
+    assert!(!RECLAIMED.load(Ordering::SeqCst), "Limine MP response used after reclaim");
+    MP_REQUEST.get_response().expect("No MP response from Limine").bsp_lapic_id()
+}
+
+/// Physical address of the ACPI RSDP, if the firmware has one. Only valid
+/// until bootloader memory is reclaimed.
+pub fn rsdp_address() -> Option<PhysAddr> {
+    assert!(!RECLAIMED.load(Ordering::SeqCst), "Limine RSDP response used after reclaim");
+    let address = RSDP_REQUEST.get_response()?.address() as u64;
+    // older base revisions hand out an HHDM pointer instead of the physical address
+    let offset = HHDM_REQUEST.get_response()?.offset();
+    Some(if address >= offset {
+        hhdm_to_phys(VirtAddr::new(address))
+    } else {
+        PhysAddr::new(address)
+    })
+}
+
+/// Give every bootloader-reclaimable region to the frame allocator. Must run
+/// on the BSP's own stack, after all APs left Limine's stacks.
+fn reclaim_bootloader_memory() {
+    if RECLAIMED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let entries = boot_info()
+        .memory_map
+        .iter()
+        .filter(|e| e.entry_type == EntryType::BOOTLOADER_RECLAIMABLE);
+    for entry in entries {
+        unsafe { frame::add_region(entry.base, entry.length) };
+    }
+}
 
+fn init_memory(memory_map: &MemoryMapResponse) {
     let hhdm = HHDM_REQUEST.get_response().expect("no HHDM");
     let phys_offset = VirtAddr::new(hhdm.offset());
 
-    let mut mapper = unsafe { memory::init(phys_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot_info().memory_map) };
-
-    init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let kernel_address = EXECUTABLE_ADDRESS_REQUEST.get_response().expect("no executable address");
+    let kernel = KernelAddress {
+        physical_base: kernel_address.physical_base(),
+        virtual_base: kernel_address.virtual_base(),
+    };
 
-    // Map APIC base address
     unsafe {
-        memory::map_apic(&mut mapper, &mut frame_allocator).expect("Failed to map APIC");
+        memory::init(phys_offset, memory_map, kernel);
     }
 
-    init_gdt();
+    init_heap().expect("heap initialization failed");
+}
+
+pub fn init() {
+    // Map APIC base address
+    crate::apic::map_local_apic().expect("Failed to map APIC");
+
+    // platform description, needed to route interrupts in init_gdt
+    crate::acpi::init();
 
+    // HPET, the reference the APIC timer is calibrated against
+    crate::time::init();
+
+    // runtime-sized render buffers, before APs can start working on them
+    crate::framebuffer::screen::framework::init();
+
+    init_gdt();
 }
@@ -0,0 +1,307 @@
+use core::arch::naked_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+use crate::interrupt::{self, FIRST_IRQ_VECTOR};
+use crate::serial_println;
+
+/// Vector the local APIC delivers spurious interrupts on (see `apic::init_apic`).
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const NMI_VECTOR: u64 = 2;
+
+/// Runs on whichever core takes an NMI, with further NMIs blocked until it
+/// returns. It can interrupt any code, lock holders and interrupt handlers
+/// included, so it must not take locks or allocate.
+pub type NmiHandler = fn();
+
+/// The registered [`NmiHandler`] as a `fn` pointer, 0 if there is none.
+static NMI_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Install the handler NMIs (including [`IpiKind::Nmi`]) run; without one an
+/// NMI is fatal. Fails if a handler is already installed.
+///
+/// [`IpiKind::Nmi`]: crate::apic::IpiKind::Nmi
+pub fn register_nmi_handler(handler: NmiHandler) -> Result<(), &'static str> {
+    NMI_HANDLER
+        .compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst)
+        .map(|_| ())
+        .map_err(|_| "NMI handler already registered")
+}
+
+/// Remove the NMI handler; NMIs are fatal again afterwards.
+pub fn unregister_nmi_handler() {
+    NMI_HANDLER.store(0, Ordering::SeqCst);
+}
+
+/// General-purpose registers saved by the entry stubs, followed by the
+/// vector, the error code (0 if the exception has none) and the frame the
+/// CPU pushed.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RIP {:#018x}  CS {:#06x}  RFLAGS {:#018x}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "RSP {:#018x}  SS {:#06x}", self.rsp, self.ss)?;
+        writeln!(f, "RAX {:#018x}  RBX {:#018x}  RCX {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX {:#018x}  RSI {:#018x}  RDI {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP {:#018x}  R8  {:#018x}  R9  {:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10 {:#018x}  R11 {:#018x}  R12 {:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13 {:#018x}  R14 {:#018x}  R15 {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Saves every GPR, hands the frame to [`exception_dispatch`] and returns
+/// with `iretq` if it does. IRQ stubs in `interrupt` jump here as well.
+#[unsafe(naked)]
+pub(crate) extern "C" fn exception_common() {
+    naked_asm!(
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        // 15 registers + vector + error code keep the CPU's 16-byte alignment
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        // drop vector and error code
+        "add rsp, 16",
+        "iretq",
+        dispatch = sym exception_dispatch,
+    );
+}
+
+/// Entry stub for a vector; pushes a dummy error code unless the CPU
+/// already pushed one.
+macro_rules! exception_stub {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+}
+
+exception_stub!(divide_error_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(nmi_stub, 2);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub!(invalid_tss_stub, 10, error_code);
+exception_stub!(segment_not_present_stub, 11, error_code);
+exception_stub!(stack_segment_stub, 12, error_code);
+exception_stub!(general_protection_stub, 13, error_code);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub!(alignment_check_stub, 17, error_code);
+exception_stub!(machine_check_stub, 18);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub!(control_protection_stub, 21, error_code);
+exception_stub!(hv_injection_stub, 28);
+exception_stub!(vmm_communication_stub, 29, error_code);
+exception_stub!(security_stub, 30, error_code);
+
+/// The local APIC does not expect an EOI for a spurious interrupt and there
+/// is nothing to handle, so this returns straight away without touching any
+/// register.
+#[unsafe(naked)]
+extern "C" fn spurious_stub() {
+    naked_asm!("iretq");
+}
+
+/// Point every exception without a dedicated handler at the
+/// register-dumping stubs, and the APIC spurious vector at its no-op stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let addr = |stub: extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error_stub));
+        idt.debug.set_handler_addr(addr(debug_stub));
+        idt.non_maskable_interrupt.set_handler_addr(addr(nmi_stub));
+        idt.overflow.set_handler_addr(addr(overflow_stub));
+        idt.bound_range_exceeded.set_handler_addr(addr(bound_range_stub));
+        idt.invalid_opcode.set_handler_addr(addr(invalid_opcode_stub));
+        idt.device_not_available.set_handler_addr(addr(device_not_available_stub));
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss_stub));
+        idt.segment_not_present.set_handler_addr(addr(segment_not_present_stub));
+        idt.stack_segment_fault.set_handler_addr(addr(stack_segment_stub));
+        idt.general_protection_fault.set_handler_addr(addr(general_protection_stub));
+        idt.x87_floating_point.set_handler_addr(addr(x87_floating_point_stub));
+        idt.alignment_check.set_handler_addr(addr(alignment_check_stub));
+        idt.machine_check.set_handler_addr(addr(machine_check_stub));
+        idt.simd_floating_point.set_handler_addr(addr(simd_floating_point_stub));
+        idt.virtualization.set_handler_addr(addr(virtualization_stub));
+        idt.cp_protection_exception.set_handler_addr(addr(control_protection_stub));
+        idt.hv_injection_exception.set_handler_addr(addr(hv_injection_stub));
+        idt.vmm_communication_exception.set_handler_addr(addr(vmm_communication_stub));
+        idt.security_exception.set_handler_addr(addr(security_stub));
+        idt[SPURIOUS_VECTOR].set_handler_addr(addr(spurious_stub));
+    }
+}
+
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "#DE divide error",
+        1 => "#DB debug",
+        2 => "NMI",
+        4 => "#OF overflow",
+        5 => "#BR bound range exceeded",
+        6 => "#UD invalid opcode",
+        7 => "#NM device not available",
+        10 => "#TS invalid TSS",
+        11 => "#NP segment not present",
+        12 => "#SS stack-segment fault",
+        13 => "#GP general protection fault",
+        16 => "#MF x87 floating-point error",
+        17 => "#AC alignment check",
+        18 => "#MC machine check",
+        19 => "#XM SIMD floating-point exception",
+        20 => "#VE virtualization exception",
+        21 => "#CP control protection exception",
+        28 => "#HV hypervisor injection",
+        29 => "#VC VMM communication exception",
+        30 => "#SX security exception",
+        _ => "unknown exception",
+    }
+}
+
+/// Decoded form of an exception's error code.
+pub(crate) struct ErrorCode {
+    pub vector: u64,
+    pub code: u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.code)?;
+        match self.vector {
+            // selector error code
+            10..=13 if self.code != 0 => {
+                let table = match (self.code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, " ({} index {:#x}", table, (self.code >> 3) & 0x1FFF)?;
+                if self.code & 1 != 0 {
+                    write!(f, ", external")?;
+                }
+                write!(f, ")")
+            }
+            21 => {
+                let kind = match self.code & 0x7FFF {
+                    1 => "near RET",
+                    2 => "far RET/IRET",
+                    3 => "missing ENDBRANCH",
+                    4 => "RSTORSSP",
+                    5 => "SETSSBSY",
+                    _ => "unknown",
+                };
+                write!(f, " ({}{})", kind, if self.code & (1 << 15) != 0 { ", enclave" } else { "" })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Called by [`exception_common`] with the saved state. IRQ vectors go on
+/// to their registered handler and NMIs to the NMI handler if there is one;
+/// every other exception routed here is fatal.
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    if (FIRST_IRQ_VECTOR as u64..SPURIOUS_VECTOR as u64).contains(&frame.vector) {
+        interrupt::dispatch_irq(frame.vector as u8);
+        return;
+    }
+    if frame.vector == NMI_VECTOR {
+        let handler = NMI_HANDLER.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: NmiHandler = unsafe { core::mem::transmute(handler) };
+            handler();
+            return;
+        }
+    }
+
+    let name = exception_name(frame.vector);
+    serial_println!("EXCEPTION: {} (vector {})", name, frame.vector);
+    serial_println!(
+        "Error Code: {}",
+        ErrorCode {
+            vector: frame.vector,
+            code: frame.error_code
+        }
+    );
+    serial_println!("{}", frame);
+    panic!("unhandled {} at RIP {:#x}", name, frame.rip);
+}
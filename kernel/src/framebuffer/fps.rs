@@ -4,46 +4,94 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 
 use super::writer::WRITER;
+use crate::time::{self, timer, Duration, Instant};
 
 static FRAME_COUNT: AtomicU32 = AtomicU32::new(0);
 
+/// How often the FPS readout is refreshed.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the counter checks whether the readout is due.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct FpsCounter {
-    last_print_ticks: u32,
-    ticks: AtomicU32,
+    last_update: Option<Instant>,
     last_fps: AtomicU32,
 }
 
 impl FpsCounter {
     pub const fn new() -> Self {
         Self {
-            last_print_ticks: 0,
-            ticks: AtomicU32::new(0),
+            last_update: None,
             last_fps: AtomicU32::new(0),
         }
     }
 
+    /// Called from a periodic timer; its rate only decides how promptly the
+    /// readout refreshes, the clock measures the actual interval.
     pub fn tick(&mut self) {
-        let ticks = self.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let last_update = *self.last_update.get_or_insert(now);
+        let elapsed = now - last_update;
 
-        // Calculate FPS every 100 ticks (1 second at 100Hz timer)
-        if ticks - self.last_print_ticks >= 100 {
-            let frames = FRAME_COUNT.swap(0, Ordering::Relaxed);
-            // frames per 100 ticks = frames per second (since timer is 100Hz)
-            let fps = frames;
+        if elapsed >= UPDATE_INTERVAL {
+            let frames = FRAME_COUNT.swap(0, Ordering::Relaxed) as u128;
+            let fps = (frames * 1_000_000_000 + elapsed.as_nanos() / 2) / elapsed.as_nanos();
+            let fps = fps as u32;
             self.last_fps.store(fps, Ordering::Relaxed);
-            self.last_print_ticks = ticks;
+            self.last_update = Some(now);
 
             // Print FPS to the screen
             let mut writer = WRITER.lock();
             writer.write_str_at(&fps.to_string(), 0, 5);
         }
     }
+
+    pub fn last_fps(&self) -> u32 {
+        self.last_fps.load(Ordering::Relaxed)
+    }
+}
+
+/// Holds a render loop to a fixed frame rate by waiting out the rest of each
+/// frame's time slot on the monotonic clock.
+pub struct FramePacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl FramePacer {
+    pub fn new(fps: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / fps.max(1),
+            next: None,
+        }
+    }
+
+    /// Wait until the next frame is due. A loop that fell more than a frame
+    /// behind starts over from now instead of rushing to catch up.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let next = *self.next.get_or_insert(now);
+        if next > now {
+            time::busy_wait(next - now);
+            self.next = Some(next + self.interval);
+        } else if now - next > self.interval {
+            self.next = Some(now + self.interval);
+        } else {
+            self.next = Some(next + self.interval);
+        }
+    }
 }
 
 lazy_static! {
     pub static ref FPS_COUNTER: Mutex<FpsCounter> = Mutex::new(FpsCounter::new());
 }
 
+/// Start refreshing the FPS readout from a periodic timer on the calling CPU.
+pub fn start() {
+    timer::schedule_periodic(TICK_INTERVAL, || FPS_COUNTER.lock().tick());
+}
+
 pub fn increment_frame_count() {
     FRAME_COUNT.fetch_add(1, Ordering::Relaxed);
 }
@@ -4,31 +4,45 @@ pub mod writer;
 
 use core::fmt;
 
-use crate::{boot::boot_info, serial_println};
+use crate::memory::{
+    mmio::{map_mmio, CacheType, MmioRegion},
+};
+use crate::{
+    boot::{boot_info, FramebufferInfo},
+    serial_println,
+};
 use lazy_static::lazy_static;
-use limine::framebuffer::Framebuffer;
 use writer::WRITER;
 
 pub struct Buffer {
-    fb: &'static Framebuffer<'static>,
+    fb: &'static FramebufferInfo,
+    /// Write-combining mapping of the framebuffer memory.
+    mmio: MmioRegion,
 }
 
 impl Buffer {
     fn new() -> Self {
-        Self {
-            fb: &boot_info().framebuffer,
-        }
+        let fb = &boot_info().framebuffer;
+        let size = (fb.pitch * fb.height) as usize;
+        let mmio = unsafe { map_mmio(fb.phys_addr, size, CacheType::WriteCombining) }
+            .expect("failed to map the framebuffer");
+        Self { fb, mmio }
+    }
+
+    #[inline]
+    fn addr(&self) -> *mut u8 {
+        self.mmio.as_mut_ptr()
     }
 
     pub fn write_while_screen(&self) {
-        let h = self.fb.height();
-        let w = self.fb.width();
+        let h = self.fb.height;
+        let w = self.fb.width;
 
         let total_pixels = w * h;
         let color_u32: u32 = 0x00FFFFFF;
 
         unsafe {
-            let fb_base = self.fb.addr();
+            let fb_base = self.addr();
             let pixel_ptr = fb_base as *mut u32;
 
             let buffer_slice = core::slice::from_raw_parts_mut(pixel_ptr, total_pixels as usize);
@@ -38,15 +52,14 @@ impl Buffer {
     }
 
     pub fn write_pixel(&self, x: u64, y: u64, color: u32) {
-        if (x >= self.fb.height()) || (y >= self.fb.width()) {
+        if (x >= self.fb.height) || (y >= self.fb.width) {
             serial_println!("Buffer out of bounds: {}x{}", x, y);
             return;
         }
 
-        let offset = (x * self.fb.pitch() + y * 4) as usize;
+        let offset = (x * self.fb.pitch + y * 4) as usize;
         unsafe {
-            self.fb
-                .addr()
+            self.addr()
                 .add(offset)
                 .cast::<u32>()
                 .write_volatile(color);
@@ -54,8 +67,8 @@ impl Buffer {
     }
 
     pub fn write_frame(&self, src: &[u32], src_w: u64, src_h: u64, dst_col: u64, dst_row: u64) {
-        let fb_width = self.fb.width() as u64;
-        let fb_height = self.fb.height() as u64;
+        let fb_width = self.fb.width as u64;
+        let fb_height = self.fb.height as u64;
         if src_w == 0 || src_h == 0 {
             return;
         }
@@ -86,11 +99,11 @@ impl Buffer {
 
         // Ensure the framebuffer's pitch can hold the source row in bytes.
         let bytes_per_src_row = (src_w as u64).checked_mul(4).unwrap_or(u64::MAX);
-        if bytes_per_src_row > self.fb.pitch() {
+        if bytes_per_src_row > self.fb.pitch {
             serial_println!(
                 "write_frame: src row bytes ({}) > fb pitch ({}) — cannot copy linearly",
                 bytes_per_src_row,
-                self.fb.pitch()
+                self.fb.pitch
             );
             return;
         }
@@ -98,9 +111,9 @@ impl Buffer {
         // perform per-scanline copy
         for row in 0..(src_h as usize) {
             let src_row_ptr = unsafe { src.as_ptr().add(row * src_w as usize) };
-            let dst_offset = ((dst_row + row as u64) * self.fb.pitch() + dst_col * 4) as usize;
+            let dst_offset = ((dst_row + row as u64) * self.fb.pitch + dst_col * 4) as usize;
             unsafe {
-                let fb_base = self.fb.addr();
+                let fb_base = self.addr();
                 let dst_ptr = fb_base.add(dst_offset) as *mut u32;
                 core::ptr::copy_nonoverlapping(src_row_ptr, dst_ptr, src_w as usize);
             }
@@ -108,17 +121,17 @@ impl Buffer {
     }
 
     pub fn scroll_lines(&self, lines: u64) {
-        let height = self.fb.height();
+        let height = self.fb.height;
         if lines == 0 || lines >= height {
             self.clear_rows(0, height);
             return;
         }
 
-        let pitch = self.fb.pitch();
+        let pitch = self.fb.pitch;
         let bytes_per_row = pitch as u64;
 
         let src_offset = (lines * pitch) as usize;
-        let dst = self.fb.addr();
+        let dst = self.addr();
         let src = unsafe { dst.add(src_offset) };
         let bytes_to_move = ((height - lines) * bytes_per_row) as usize;
 
@@ -130,12 +143,12 @@ impl Buffer {
     }
 
     pub fn clear_rows(&self, start_row: u64, count: u64) {
-        let pitch = self.fb.pitch();
+        let pitch = self.fb.pitch;
         let bytes_per_row = pitch as usize;
         let start_offset = (start_row * pitch) as usize;
         let total_bytes = (count * bytes_per_row as u64) as usize;
 
-        let base = unsafe { self.fb.addr().add(start_offset) };
+        let base = unsafe { self.addr().add(start_offset) };
 
         unsafe {
             core::ptr::write_bytes(base, 0, total_bytes);
@@ -4,20 +4,23 @@ use core::{
     sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
 
+use spin::Once;
+
 use crate::boot::boot_info;
 
 use crate::framebuffer::{fps::increment_frame_count, BUFFER};
+use crate::memory::vmalloc::{vmalloc, VmRegion};
 
-pub const SCALED_W: usize = 1024;
-const SCALED_H: usize = 960;
-const MAX_WORKERS: usize = 8; // clamp to 8 workers (APs only)
+/// Size of the frames handed to `Screen::write_buffer`.
+pub const SRC_W: usize = 256;
+pub const SRC_H: usize = 240;
 
 /// Global work descriptor (signals and parameters)
 #[repr(C)]
 pub struct FrameWork {
     pub seq: AtomicU64,         // sequence id changed for each frame
-    pub src_ptr: AtomicUsize,   // pointer to source [[u32;256];240] as usize
-    pub parts: AtomicUsize,     // number of worker parts (even, <= MAX_WORKERS)
+    pub src_ptr: AtomicUsize,   // pointer to source [[u32;SRC_W];SRC_H] as usize
+    pub parts: AtomicUsize,     // number of worker parts (even, <= AP count)
     pub pending: AtomicUsize,   // number of workers still pending
     pub start_col: AtomicUsize, // horizontal start column offset (u64 in write_frame)
 }
@@ -36,30 +39,67 @@ impl FrameWork {
 
 pub static WORK: FrameWork = FrameWork::new();
 
-/// Static mutable scaled buffer.
+/// The source frame upscaled by an integer factor that fits the real
+/// framebuffer resolution.
 /// SAFETY: multiple APs will write to disjoint stripes only (guaranteed by code).
-/// We use `static mut` and write to it inside `unsafe` blocks.
-pub static mut SCALED_BUFFER: [u32; SCALED_W * SCALED_H] = [0u32; SCALED_W * SCALED_H];
-
-#[inline(always)]
-fn scaled_buf_ptr_u32() -> *mut u32 {
-    // addr_of_mut! obtains an address without creating a reference to the static.
-    // cast to *mut u32 so we can index by element.
-    core::ptr::addr_of_mut!(SCALED_BUFFER) as *mut u32
+pub struct ScaledBuffer {
+    region: VmRegion,
+    pub width: usize,
+    pub height: usize,
+    pub scale: usize,
+}
+
+impl ScaledBuffer {
+    fn new() -> Self {
+        let fb = &boot_info().framebuffer;
+        let scale = ((fb.width as usize / SRC_W).min(fb.height as usize / SRC_H)).max(1);
+        let (width, height) = (SRC_W * scale, SRC_H * scale);
+        let region = vmalloc(width * height * 4).expect("failed to allocate the scaled buffer");
+        Self { region, width, height, scale }
+    }
+
+    #[inline(always)]
+    pub fn as_mut_ptr(&self) -> *mut u32 {
+        self.region.as_mut_ptr()
+    }
+
+    /// Upscale rows `y0..y1` of the output from `src`.
+    ///
+    /// # Safety
+    /// No one else may access those rows at the same time.
+    pub unsafe fn scale_rows(&self, src: &[[u32; SRC_W]; SRC_H], y0: usize, y1: usize) {
+        for y in y0..y1 {
+            let row = unsafe { slice::from_raw_parts_mut(self.as_mut_ptr().add(y * self.width), self.width) };
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = src[y / self.scale][x / self.scale];
+            }
+        }
+    }
+
+    /// Whole buffer as a slice.
+    ///
+    /// # Safety
+    /// No one may be writing to the buffer at the same time.
+    pub unsafe fn as_slice(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.as_mut_ptr(), self.width * self.height) }
+    }
+}
+
+static SCALED_BUFFER: Once<ScaledBuffer> = Once::new();
+
+/// Allocate the scaled buffer for the current resolution.
+pub fn init() {
+    SCALED_BUFFER.call_once(ScaledBuffer::new);
+}
+
+pub fn scaled_buffer() -> &'static ScaledBuffer {
+    SCALED_BUFFER.get().expect("scaled buffer not initialized")
 }
 
 /// Choose worker count excluding BSP (BSP not participating).
 /// Returns 0 if not enough APs (i.e., available_aps < 2).
 pub fn choose_worker_count_excluding_bsp() -> usize {
-    let cpus = boot_info().cpus.len();
-    // need total >= 3 (BSP + 2 APs)
-    if cpus < 3 {
-        return 0;
-    }
-    let mut available_aps = cpus - 1; // exclude BSP
-    if available_aps > MAX_WORKERS {
-        available_aps = MAX_WORKERS;
-    }
+    let mut available_aps = boot_info().ap_count();
     // make it even
     if available_aps % 2 == 1 {
         available_aps -= 1;
@@ -71,11 +111,10 @@ pub fn choose_worker_count_excluding_bsp() -> usize {
 }
 
 /// Per-AP worker loop. Called from ap_main_direct after GDT/TSS/IDT setup.
-/// `core_index` is the index inside boot_info().cpus for this CPU (BSP excluded earlier).
+/// `core_index` is the logical number of this CPU; worker `i` is AP `i`,
+/// so the stripes go to the same cores on every boot.
 pub fn ap_worker_loop(core_index: usize) -> ! {
-    // Assume BSP is boot_info().cpus[0]. So AP local index = core_index - 1.
-    // If BSP isn't index 0 in your environment, adapt accordingly.
-    let ap_local_index = core_index.checked_sub(1).unwrap_or(usize::MAX);
+    let worker_index = boot_info().ap_index(core_index);
 
     // last_seen sequence to detect new frames
     let mut last_seen = WORK.seq.load(Ordering::Acquire);
@@ -96,19 +135,21 @@ pub fn ap_worker_loop(core_index: usize) -> ! {
         }
 
         // If this AP isn't part of current work, skip
-        if ap_local_index == usize::MAX || ap_local_index >= parts {
+        let Some(ap_local_index) = worker_index.filter(|&i| i < parts) else {
             continue;
-        }
+        };
 
         // get source pointer (pointer validity guaranteed by writer)
         let src_usize = WORK.src_ptr.load(Ordering::Acquire);
         let start_col = WORK.start_col.load(Ordering::Acquire) as u64;
 
+        let scaled = scaled_buffer();
+
         // compute stripe rows (even split by rows)
-        let stripe_h = SCALED_H / parts;
+        let stripe_h = scaled.height / parts;
         let y0 = ap_local_index * stripe_h;
         let y1 = if ap_local_index + 1 == parts {
-            SCALED_H
+            scaled.height
         } else {
             (ap_local_index + 1) * stripe_h
         };
@@ -121,25 +162,8 @@ pub fn ap_worker_loop(core_index: usize) -> ! {
 
         // SAFELY write to only our stripe in the global scaled buffer.
         unsafe {
-            // get references
-            let src: &[[u32; 256]; 240] = &*(src_usize as *const [[u32; 256]; 240]);
-            let out_ptr = scaled_buf_ptr_u32();
-
-            for y in y0..y1 {
-                let mut row_buffer: [u32; SCALED_W] = [0; SCALED_W];
-
-                for x in 0..SCALED_W {
-                    let pixel = src[y / 4][x / 4];
-                    row_buffer[x] = pixel;
-                }
-
-                let out_row_offset = y * SCALED_W;
-                core::ptr::copy_nonoverlapping(
-                    row_buffer.as_ptr(),
-                    out_ptr.add(out_row_offset),
-                    SCALED_W,
-                );
-            }
+            let src: &[[u32; SRC_W]; SRC_H] = &*(src_usize as *const [[u32; SRC_W]; SRC_H]);
+            scaled.scale_rows(src, y0, y1);
         } // end unsafe stripe write
 
         // mark this worker done
@@ -147,14 +171,10 @@ pub fn ap_worker_loop(core_index: usize) -> ! {
 
         // If we are the last worker, perform the final flush to the hardware buffer.
         if prev == 1 {
-            unsafe {
-                // SCALED_BUFFER now fully written by all APs; pass it to BUFFER.
-                // make a slice &SCALED_BUFFER
-                let out_ptr = scaled_buf_ptr_u32() as *const u32;
-                let out_slice: &[u32] = slice::from_raw_parts(out_ptr, SCALED_W * SCALED_H);
-                BUFFER.write_frame(out_slice, SCALED_W as u64, SCALED_H as u64, start_col, 0);
-                increment_frame_count();
-            }
+            // SCALED_BUFFER now fully written by all APs; pass it to BUFFER.
+            let out_slice = unsafe { scaled.as_slice() };
+            BUFFER.write_frame(out_slice, scaled.width as u64, scaled.height as u64, start_col, 0);
+            increment_frame_count();
         }
     }
 }
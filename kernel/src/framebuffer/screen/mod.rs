@@ -1,19 +1,34 @@
 pub mod framework;
 pub mod tv;
-use super::{fps::increment_frame_count, BUFFER};
+use super::{fps::{increment_frame_count, FramePacer}, BUFFER};
 use crate::serial_println;
 use core::{hint::spin_loop, sync::atomic::Ordering};
-use framework::{choose_worker_count_excluding_bsp, WORK};
+use framework::{choose_worker_count_excluding_bsp, scaled_buffer, SRC_H, SRC_W, WORK};
 use lazy_static::lazy_static;
 use spin::Mutex;
 pub struct Screen {
     width_offset: u64,
+    pacer: Option<FramePacer>,
 }
 impl Screen {
     pub fn new() -> Self {
-        Self { width_offset: 320 }
+        Self { width_offset: 320, pacer: None }
     }
-    pub fn write_buffer(&mut self, buffer: &[[u32; 256]; 240]) {
+
+    /// Cap how many frames per second the `write_buffer*` calls present;
+    /// `None` renders as fast as possible.
+    pub fn set_frame_limit(&mut self, fps: Option<u32>) {
+        self.pacer = fps.map(FramePacer::new);
+    }
+
+    fn pace(&mut self) {
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.wait();
+        }
+    }
+
+    pub fn write_buffer(&mut self, buffer: &[[u32; SRC_W]; SRC_H]) {
+        self.pace();
         // choose number of workers (APs only)
         let parts = choose_worker_count_excluding_bsp();
         if parts == 0 {
@@ -21,7 +36,7 @@ impl Screen {
             return;
         }
         let start_col = self.width_offset as usize;
-        let src_ptr = buffer as *const [[u32; 256]; 240] as usize;
+        let src_ptr = buffer as *const [[u32; SRC_W]; SRC_H] as usize;
         // publish work: set pointer + params before bumping seq
         WORK.src_ptr.store(src_ptr, Ordering::Release);
         WORK.start_col.store(start_col, Ordering::Release);
@@ -35,18 +50,17 @@ impl Screen {
         }
     }
 
-    pub fn write_buffer_single(&mut self, buffer: &[[u32; 256]; 240]) {
+    pub fn write_buffer_single(&mut self, buffer: &[[u32; SRC_W]; SRC_H]) {
+        self.pace();
         let start_col = self.width_offset;
-        let mut scaled_buffer: [[u32; 1024]; 960] = [[0; 1024]; 960];
-        for y in 0..960 {
-            for x in 0..1024 {
-                scaled_buffer[y][x] = buffer[y / 4][x / 4];
-            }
-        }
-        let flat: &[u32] = unsafe {
-            core::slice::from_raw_parts(scaled_buffer.as_ptr() as *const u32, 1024 * 960)
+        // workers only touch the scaled buffer from write_buffer, which also
+        // needs the SCREEN lock we are holding
+        let scaled = scaled_buffer();
+        let flat = unsafe {
+            scaled.scale_rows(buffer, 0, scaled.height);
+            scaled.as_slice()
         };
-        BUFFER.write_frame(flat, 1024, 960, start_col, 0);
+        BUFFER.write_frame(flat, scaled.width as u64, scaled.height as u64, start_col, 0);
         increment_frame_count();
     }
 }
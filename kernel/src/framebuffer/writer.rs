@@ -151,7 +151,7 @@ impl Writer {
 
     fn scroll(&self) {
         let buffer = &BUFFER;
-        let scale = buffer.fb.height() / self.height as u64;
+        let scale = buffer.fb.height / self.height as u64;
         buffer.scroll_lines(scale * 8);
     }
 }
@@ -1,89 +1,112 @@
-use core::mem::MaybeUninit;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use core::u16;
-use alloc::boxed::Box;
-use lazy_static::lazy_static;
+use core::arch::asm;
+use alloc::vec::Vec;
 use limine::mp::Cpu;
-use spin::Mutex;
-use x86_64::instructions::tables::load_tss;
+use spin::Once;
+use x86_64::instructions::tables::{lgdt, load_tss};
 use x86_64::registers::segmentation::Segment;
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::gdt::{Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::DescriptorTablePointer;
 use x86_64::{PrivilegeLevel, VirtAddr};
 
-use crate::boot::boot_info;
+use crate::boot::{boot_info, limine_cpus, BSP_INDEX};
+use crate::percpu::PerCpu;
+use crate::memory::stack::{allocate_stack, GuardedStack};
+use crate::time::{Duration, Instant};
 
-/// tune these values as needed
-pub const MAX_CPUS: usize = 12;
-pub const KERNEL_STACK_SIZE: usize = 5 * 1024 * 1024; // 5 MB
+/// Every CPU gets a kernel stack backed up front: a stack can't be faulted in
+/// on demand, since the fault would have nowhere to push its frame. The
+/// guard page below turns an overflow into a clean report.
+pub const KERNEL_STACK_SIZE: usize = 256 * 1024; // 256 KiB
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 // Choose a reasonable size for the DF stack per CPU:
 const DF_STACK_SIZE: usize = 64 * 1024; // 64 KiB (adjust if you want bigger)
 
-/// Static per-cpu kernel stacks (stable addresses)
-#[unsafe(link_section = ".bss.stack")]
-static mut KERNEL_STACKS: [[u8; KERNEL_STACK_SIZE]; MAX_CPUS] =
-    [[0; KERNEL_STACK_SIZE]; MAX_CPUS];
-
-#[unsafe(link_section = ".bss.stack")]
-static mut DF_STACKS: [[u8; DF_STACK_SIZE]; MAX_CPUS] = [[0; DF_STACK_SIZE]; MAX_CPUS];
-// The above weird initializer avoids long compile-time expressions in some toolchains.
-// If your toolchain rejects it, replace with: `[[0u8; KERNEL_STACK_SIZE]; MAX_CPUS]` under nightly.
-
-/// Per-cpu TSS storage (stable addresses). We'll initialize for `num_cpus` at runtime.
-static mut PERCPU_TSS: [MaybeUninit<TaskStateSegment>; MAX_CPUS] =
-    unsafe { MaybeUninit::<[MaybeUninit<TaskStateSegment>; MAX_CPUS]>::uninit().assume_init() };
-
-/// The TSS selectors for each cpu will be stored here after building GDT
-static mut PERCPU_TSS_SELECTORS: [SegmentSelector; MAX_CPUS] = [SegmentSelector::new(0,PrivilegeLevel::Ring0); MAX_CPUS];
-
-// A single shared GDT and code/data selectors. The GDT itself is created on BSP
-// in `init_percpu_gdt()` and then loaded. APs should call `load_gdt()` and then `load_tss_for_core()`.
-lazy_static! {
-    // store &'static GlobalDescriptorTable<32> so load() can be called
-    static ref SHARED_GDT: Mutex<Option<(&'static GlobalDescriptorTable<32>, SegmentSelector, SegmentSelector)>> =
-        Mutex::new(None);
-}
+/// Descriptors a GDT can hold at most: its limit is 16 bits of bytes. With
+/// the null, code and data entries and two slots per TSS that is room for
+/// 4094 CPUs.
+const MAX_GDT_ENTRIES: usize = 8192;
 
-/// How many CPUs we built the GDT for
-static INITIALIZED_CPUS: AtomicUsize = AtomicUsize::new(0);
+/// Per-cpu kernel and double fault stacks, each with an unmapped guard page
+/// below it. Allocated once in `init_gdt` for the CPUs actually present.
+struct CpuStacks {
+    kernel: GuardedStack,
+    double_fault: GuardedStack,
+}
 
-/// Initialize the shared GDT with per-cpu TSS descriptors.
-/// Call on BSP *after* you know how many CPUs you will support (e.g. boot_info.cpus.len()).
-/// This builds the GDT containing code/data + N TSS entries and loads it (lgdt).
-pub fn init_percpu_gdt(num_cpus: usize) {
-    assert!(num_cpus <= MAX_CPUS);
+static CPU_STACKS: Once<Vec<CpuStacks>> = Once::new();
+
+/// One TSS per cpu, never resized so their addresses stay stable.
+static PERCPU_TSS: Once<Vec<TaskStateSegment>> = Once::new();
+
+/// The GDT all cores share, sized for the CPUs present. It is created on BSP
+/// in `init_percpu_gdt()` and then loaded. APs should call `load_gdt()` and
+/// then `load_tss_for_core()`.
+struct SharedGdt {
+    /// Raw descriptors: null, code, data, then a two-slot TSS descriptor per cpu.
+    entries: Vec<u64>,
+    code: SegmentSelector,
+    data: SegmentSelector,
+    /// TSS selector of each cpu.
+    tss: Vec<SegmentSelector>,
+}
 
-    // 1) Initialize PERCPU_TSS storage in place
-    for i in 0..num_cpus {
-        unsafe {
-            PERCPU_TSS[i].as_mut_ptr().write(TaskStateSegment::new());
+impl SharedGdt {
+    fn append(&mut self, descriptor: Descriptor) -> SegmentSelector {
+        let index = self.entries.len() as u16;
+        match descriptor {
+            Descriptor::UserSegment(value) => self.entries.push(value),
+            Descriptor::SystemSegment(low, high) => self.entries.extend([low, high]),
         }
+        SegmentSelector::new(index, PrivilegeLevel::Ring0)
     }
+}
 
-    // 2) Build GDT: code, data, and a TSS descriptor per cpu
-    let mut gdt: GlobalDescriptorTable<32> = GlobalDescriptorTable::<32>::empty();
-    let code_sel = gdt.append(Descriptor::kernel_code_segment());
-    let data_sel = gdt.append(Descriptor::kernel_data_segment());
-
-    for i in 0..num_cpus {
-        let tss_ref: &'static TaskStateSegment = unsafe { &*PERCPU_TSS[i].as_ptr() };
-        let tss_sel = gdt.append(Descriptor::tss_segment(tss_ref));
-        unsafe { PERCPU_TSS_SELECTORS[i] = tss_sel; }
-    }
+static SHARED_GDT: Once<SharedGdt> = Once::new();
 
-    // Leak the GDT so it becomes &'static
-    let gdt_box = Box::new(gdt);
-    let gdt_static_ref: &'static GlobalDescriptorTable<32> = Box::leak(gdt_box);
+/// APs that made it onto their own kernel stack
+static APS_ONLINE: AtomicUsize = AtomicUsize::new(0);
 
-    // Store the static ref + selectors in SHARED_GDT
-    {
-        let mut lock = SHARED_GDT.lock();
-        *lock = Some((gdt_static_ref, code_sel, data_sel));
-    }
+/// How long the BSP waits for every AP to check in before giving up.
+const AP_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
 
-    INITIALIZED_CPUS.store(num_cpus, Ordering::SeqCst);
+/// Build a TSS per cpu, pointing at the stacks from `allocate_cpu_stacks`,
+/// and the shared GDT with code/data + N TSS entries, then load it (lgdt).
+/// Call on BSP once, with the number of CPUs Limine reported.
+pub fn init_percpu_gdt(num_cpus: usize) {
+    assert!(3 + 2 * num_cpus <= MAX_GDT_ENTRIES, "too many CPUs for one GDT");
+
+    // 1) a TSS per cpu with its kernel stack and DF IST already in place
+    let tss = PERCPU_TSS.call_once(|| {
+        (0..num_cpus)
+            .map(|i| {
+                let mut tss = TaskStateSegment::new();
+                tss.privilege_stack_table[0] = kernel_stack_top(i);
+                tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = df_stack_top_for(i);
+                tss
+            })
+            .collect()
+    });
+
+    // 2) Build GDT: code, data, and a TSS descriptor per cpu
+    SHARED_GDT.call_once(|| {
+        let mut gdt = SharedGdt {
+            entries: Vec::with_capacity(3 + 2 * num_cpus),
+            code: SegmentSelector::NULL,
+            data: SegmentSelector::NULL,
+            tss: Vec::with_capacity(num_cpus),
+        };
+        gdt.entries.push(0);
+        gdt.code = gdt.append(Descriptor::kernel_code_segment());
+        gdt.data = gdt.append(Descriptor::kernel_data_segment());
+        for tss in tss {
+            let selector = gdt.append(Descriptor::tss_segment(tss));
+            gdt.tss.push(selector);
+        }
+        gdt
+    });
 
     // load GDT on BSP immediately
     load_gdt();
@@ -91,59 +114,44 @@ pub fn init_percpu_gdt(num_cpus: usize) {
 
 /// Return how many CPUs were initialized
 pub fn initialized_cpus() -> usize {
-    INITIALIZED_CPUS.load(Ordering::SeqCst)
+    PERCPU_TSS.get().map_or(0, Vec::len)
 }
 
-/// Set the kernel stack top (rsp0) for a given cpu index in its TSS.
-/// Call this on BSP before waking APs (so the TSS already has the stack).
-pub fn set_stack_for_cpu(cpu_index: usize, stack_top: VirtAddr) {
-    assert!(cpu_index < initialized_cpus());
-    unsafe {
-        let tss = &mut *PERCPU_TSS[cpu_index].as_mut_ptr();
-        tss.privilege_stack_table[0] = stack_top;
-    }
+fn cpu_stacks() -> &'static [CpuStacks] {
+    CPU_STACKS.get().expect("per-cpu stacks not allocated")
 }
 
 /// Helper: return kernel stack top for cpu_index (VirtAddr)
 pub fn kernel_stack_top(cpu_index: usize) -> VirtAddr {
-    unsafe {
-        let base = KERNEL_STACKS[cpu_index].as_ptr() as u64;
-        let top = base + KERNEL_STACK_SIZE as u64;
-        // Align to 16 bytes
-        let aligned_top = (top / 16) * 16;
-        VirtAddr::new(aligned_top)
-    }
+    cpu_stacks()[cpu_index].kernel.top()
 }
 
-/// Optionally set IST entry for cpu
-pub fn set_ist_for_cpu(cpu_index: usize, ist_index: usize, ist_top: VirtAddr) {
-    assert!(cpu_index < initialized_cpus());
-    unsafe {
-        let tss = &mut *PERCPU_TSS[cpu_index].as_mut_ptr();
-        tss.interrupt_stack_table[ist_index] = ist_top;
-    }
+/// The TSS of `cpu_index`, for its per-cpu block.
+pub(crate) fn tss_for(cpu_index: usize) -> *const TaskStateSegment {
+    &PERCPU_TSS.get().expect("TSSs not initialized")[cpu_index]
+}
+
+fn shared_gdt() -> &'static SharedGdt {
+    SHARED_GDT.get().expect("GDT not initialized")
 }
 
 /// Load the shared GDT on the current core (safe to call on APs)
 pub fn load_gdt() {
     use x86_64::instructions::segmentation::{CS, DS, ES, SS};
 
-    // Call `load()` while holding the mutex so the GDT object lives for the lgdt op.
-    let (code_sel, data_sel) = {
-        let lock = SHARED_GDT.lock();
-        let slot = lock.as_ref().expect("GDT not initialized");
-        // call load() on the &'static GlobalDescriptorTable<32> we stored
-        slot.0.load();
-        (slot.1, slot.2)
-        // lock dropped here
+    let gdt = shared_gdt();
+    let pointer = DescriptorTablePointer {
+        limit: (gdt.entries.len() * size_of::<u64>() - 1) as u16,
+        base: VirtAddr::from_ptr(gdt.entries.as_ptr()),
     };
 
-    // Now it's safe to reload segment registers using the copied selectors.
+    // The entries live as long as the kernel, so the selectors stay valid.
     unsafe {
-        CS::set_reg(code_sel);
-        DS::set_reg(data_sel);
-        ES::set_reg(data_sel);
-        SS::set_reg(data_sel);
+        lgdt(&pointer);
+        CS::set_reg(gdt.code);
+        DS::set_reg(gdt.data);
+        ES::set_reg(gdt.data);
+        SS::set_reg(gdt.data);
     }
 }
 
@@ -151,21 +159,59 @@ pub fn load_gdt() {
 /// `core_index` must be less than `initialized_cpus()`.
 pub fn load_tss_for_core(core_index: usize) {
     assert!(core_index < initialized_cpus());
-    unsafe {
-        let sel = PERCPU_TSS_SELECTORS[core_index];
-        load_tss(sel);
-    }
+    unsafe { load_tss(shared_gdt().tss[core_index]) };
 }
 
 /// Return the top-of-stack VirtAddr for the DF stack of `cpu_index`.
 /// The returned address is 16-byte aligned.
 pub fn df_stack_top_for(cpu_index: usize) -> VirtAddr {
+    cpu_stacks()[cpu_index].double_fault.top()
+}
+
+/// If `addr` lies in the guard page of one of the per-cpu stacks, return the
+/// owning cpu index and which stack it guards.
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<(usize, &'static str)> {
+    let stacks = CPU_STACKS.get()?;
+    stacks.iter().enumerate().find_map(|(i, s)| {
+        if s.kernel.guard_contains(addr) {
+            Some((i, "kernel"))
+        } else if s.double_fault.guard_contains(addr) {
+            Some((i, "double fault"))
+        } else {
+            None
+        }
+    })
+}
+
+/// Allocate guarded kernel and double fault stacks for `num_cpus` cpus.
+fn allocate_cpu_stacks(num_cpus: usize) {
+    CPU_STACKS.call_once(|| {
+        (0..num_cpus)
+            .map(|_| CpuStacks {
+                kernel: allocate_stack(KERNEL_STACK_SIZE).expect("failed to allocate kernel stack"),
+                double_fault: allocate_stack(DF_STACK_SIZE).expect("failed to allocate DF stack"),
+            })
+            .collect()
+    });
+}
+
+/// Move the current core onto `stack_top` and call `entry(arg)` there.
+///
+/// # Safety
+/// `stack_top` must be the top of a mapped, otherwise unused stack. Nothing on
+/// the old stack may be referenced afterwards.
+pub unsafe fn switch_stack(stack_top: VirtAddr, arg: usize, entry: extern "C" fn(usize) -> !) -> ! {
     unsafe {
-        let base = DF_STACKS[cpu_index].as_ptr() as u64;
-        let top = base + DF_STACK_SIZE as u64;
-        // Ensure 16-byte alignment
-        let aligned_top = (top / 16) * 16;
-        VirtAddr::new(aligned_top)
+        asm!(
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {entry}",
+            "ud2",
+            stack = in(reg) stack_top.as_u64(),
+            entry = in(reg) entry,
+            in("rdi") arg,
+            options(noreturn)
+        );
     }
 }
 
@@ -173,79 +219,89 @@ pub fn init_gdt() {
     let boot_info = boot_info();
     let num_cpus = boot_info.cpus.len();
 
-    // 1) build per-cpu GDT and TSS storage
+    // 1) the guarded stacks, then the TSSs pointing at them and the GDT
+    //    holding those, all sized for the CPUs present
+    allocate_cpu_stacks(num_cpus);
     crate::gdt::init_percpu_gdt(num_cpus);
 
-    // 2) set per-cpu kernel stacks and DF ISTs BEFORE waking APs
-    for i in 0..num_cpus {
-        let top = crate::gdt::kernel_stack_top(i);
-        crate::gdt::set_stack_for_cpu(i, top);
-        let df_top = crate::gdt::df_stack_top_for(i);
-        crate::gdt::set_ist_for_cpu(i, DOUBLE_FAULT_IST_INDEX as usize, df_top);
-    }
-
-    // 3) load GDT & TSS for BSP (assumed BSP index 0)
+    // 2) per-cpu blocks, then GDT, TSS and GS base for BSP
+    crate::percpu::init();
     crate::gdt::load_gdt();
-    crate::gdt::load_tss_for_core(0);
+    crate::gdt::load_tss_for_core(BSP_INDEX);
+    crate::percpu::load(BSP_INDEX);
 
-    // 4) load IDT on BSP
+    // 3) load IDT on BSP, retire the PICs for the I/O APIC and hook up the
+    //    fixed interrupt handlers
     crate::interrupt::init_idt();
+    crate::ioapic::init();
 
-    // Initialize PIC first
-    unsafe {
-        let mut pics = crate::interrupt::PICS.lock();
-        pics.initialize();
-        pics.write_masks(0, 0);
-    }
-
-    // Initialize APIC and disable only PIC timer
+    // Initialize APIC
     if let Err(e) = crate::apic::init_apic() {
         panic!("Failed to initialize APIC: {}", e);
     }
 
+    crate::interrupt::init_irqs();
+    crate::ipi::init();
+    crate::ipi::mark_ready(BSP_INDEX);
+
+    crate::time::timer::init();
     if let Err(e) = crate::apic::init_apic_timer() {
         panic!("Failed to initialize APIC timer: {}", e);
     }
-    crate::apic::disable_pic_timer();
-
-    // 5) publish stack top so trampoline (or direct entry) can pick it up on AP
-    for (i, cpu) in boot_info.cpus.iter().enumerate() {
-        cpu.extra.store(crate::gdt::kernel_stack_top(i).as_u64(), core::sync::atomic::Ordering::SeqCst);
-    }
-
-    // 6) Tell Limine where APs should jump to.
-    //    Skip BSP (assumed index 0) so you don't jump BSP.
-    for (i, cpu) in boot_info.cpus.iter().enumerate() {
-        if i == 0 { continue; } // skip BSP; adapt if your BSP is not index 0
-        cpu.goto_address.write(ap_main_direct); 
+    crate::framebuffer::fps::start();
+
+    // 4) publish each AP's per-cpu block, which tells it its index, then
+    //    tell Limine where the APs should jump to. Limine's order is not
+    //    ours, so match by local APIC ID.
+    for cpu in limine_cpus() {
+        let index = boot_info.cpu_index(cpu.lapic_id).expect("CPU missing from boot info");
+        if index == BSP_INDEX {
+            continue;
+        }
+        let percpu = &crate::percpu::cpus()[index];
+        cpu.extra.store(percpu as *const PerCpu as u64, Ordering::SeqCst);
+        cpu.goto_address.write(ap_main_direct);
     }
 
-    // 7) Now we can enable interrupts on BSP (after GDT/TSS/IDT are set)
+    // 5) Now we can enable interrupts on BSP (after GDT/TSS/IDT are set)
     x86_64::instructions::interrupts::enable();
 
 }
 
 
+/// Spin until every AP has left its Limine stack, after which the
+/// bootloader-reclaimable memory is no longer in use.
+pub fn wait_for_aps() {
+    let aps = boot_info().ap_count();
+    let deadline = Instant::now() + AP_STARTUP_TIMEOUT;
+    while APS_ONLINE.load(Ordering::SeqCst) < aps {
+        if Instant::now() > deadline {
+            panic!("only {} of {} APs came online", APS_ONLINE.load(Ordering::SeqCst), aps);
+        }
+        core::hint::spin_loop();
+    }
+}
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn ap_main_direct(cpu_ptr: &Cpu) -> ! {
+    // leave Limine's page tables for the shared kernel ones
+    unsafe { crate::memory::paging::load_kernel_page_table() };
 
-    // find core index by pointer equality to boot_info.cpus entries
-    let core_index = {
-        let boot = boot_info(); // your function that returns the Limine boot info struct
-        let mut found = None;
-        for (i, c) in boot.cpus.iter().enumerate() {
-            if core::ptr::eq(*c, cpu_ptr) {
-                found = Some(i);
-                break;
-            }
-        }
-        match found {
-            Some(i) => i,
-            None => {
-                loop { x86_64::instructions::hlt(); }
-            }
-        }
-    };
+    // same time stamp counter as the BSP before anything reads the clock
+    crate::time::tsc::init_ap();
+
+    // `extra` holds this core's per-cpu block; from here on this_cpu() works
+    let percpu = unsafe { &*(cpu_ptr.extra.load(Ordering::SeqCst) as *const PerCpu) };
+    crate::percpu::load(percpu.index());
+
+    // continue on the guarded kernel stack instead of Limine's
+    let stack_top = kernel_stack_top(percpu.index());
+    unsafe { switch_stack(stack_top, percpu.index(), ap_main_on_stack) }
+}
+
+extern "C" fn ap_main_on_stack(core_index: usize) -> ! {
+    // Limine's stack and Cpu entry are no longer referenced from here on
+    APS_ONLINE.fetch_add(1, Ordering::SeqCst);
 
     // load same shared GDT and per-core TSS
     crate::gdt::load_gdt();
@@ -258,6 +314,12 @@ pub unsafe extern "C" fn ap_main_direct(cpu_ptr: &Cpu) -> ! {
     if let Err(e) = crate::apic::init_apic() {
         panic!("Failed to initialize APIC on AP: {}", e);
     }
+    crate::ipi::mark_ready(core_index);
+
+    // one-shot timer for this core's timer queue
+    if let Err(e) = crate::apic::init_apic_timer() {
+        panic!("Failed to initialize APIC timer on AP: {}", e);
+    }
 
     // Now safe to enable interrupts on this AP
     x86_64::instructions::interrupts::enable();
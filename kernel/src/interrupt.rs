@@ -1,14 +1,23 @@
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
 use crate::apic;
-use crate::framebuffer::fps::FPS_COUNTER;
+use crate::exception::{self, SPURIOUS_VECTOR};
 use crate::gdt;
+use crate::ioapic;
+use crate::memory;
+use crate::percpu;
 use crate::print;
 use crate::println;
+use crate::serial_println;
+use crate::time;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::instructions::hlt;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -20,7 +29,9 @@ pub static PICS: spin::Mutex<ChainedPics> =
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard,
+    Keyboard = PIC_1_OFFSET + ioapic::ISA_KEYBOARD,
+    Serial = PIC_1_OFFSET + ioapic::ISA_COM1,
+    Rtc = PIC_1_OFFSET + ioapic::ISA_RTC,
 }
 
 impl InterruptIndex {
@@ -29,9 +40,180 @@ impl InterruptIndex {
     }
 }
 
+/// First vector that isn't a CPU exception.
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+
+/// Vectors handed out by [`allocate_vector`]. Everything below is kept for
+/// the legacy ISA IRQs, everything above for IPIs and the spurious vector.
+pub const DYNAMIC_VECTORS: core::ops::RangeInclusive<u8> = 0x30..=0xEF;
+
+/// Handler for a device or inter-processor interrupt; gets the vector it was
+/// raised on. Runs with interrupts disabled, EOI is sent after it returns.
+pub type IrqHandler = fn(vector: u8);
+
+/// Which interrupt controller a vector's EOI goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IrqController {
+    /// Local APIC (APIC timer, I/O APIC routed IRQs, IPIs).
+    Apic = 0,
+    /// Legacy 8259 PIC pair.
+    Pic = 1,
+}
+
+/// Registered handlers: a `fn` pointer with the [`IrqController`] for its EOI
+/// in bit 0, or 0 for a free slot. Keeping both in one word means a failed
+/// registration can't touch an owned vector and dispatch never pairs a
+/// handler with another registration's controller.
+static IRQ_HANDLERS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+static IRQ_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Bit of an `IRQ_HANDLERS` entry holding the controller; functions are
+/// always at least 2-byte aligned, so the pointer never uses it.
+const CONTROLLER_BIT: usize = 1;
+
+fn unpack(entry: usize) -> (Option<IrqHandler>, IrqController) {
+    let controller = match entry & CONTROLLER_BIT {
+        0 => IrqController::Apic,
+        _ => IrqController::Pic,
+    };
+    let handler = match entry & !CONTROLLER_BIT {
+        0 => None,
+        handler => Some(unsafe { core::mem::transmute::<usize, IrqHandler>(handler) }),
+    };
+    (handler, controller)
+}
+
+fn check_irq_vector(vector: u8) -> Result<(), &'static str> {
+    if vector < FIRST_IRQ_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err("vector is reserved for exceptions");
+    }
+    Ok(())
+}
+
+/// Install `handler` for `vector`. Fails if the vector is an exception or
+/// already taken.
+pub fn register_irq(
+    vector: u8,
+    handler: IrqHandler,
+    controller: IrqController,
+) -> Result<(), &'static str> {
+    check_irq_vector(vector)?;
+    let handler = handler as usize;
+    if handler & CONTROLLER_BIT != 0 {
+        return Err("interrupt handler is not aligned");
+    }
+    let entry = match controller {
+        IrqController::Apic => handler,
+        IrqController::Pic => handler | CONTROLLER_BIT,
+    };
+    IRQ_HANDLERS[vector as usize]
+        .compare_exchange(0, entry, Ordering::SeqCst, Ordering::SeqCst)
+        .map(|_| ())
+        .map_err(|_| "vector already has a handler")
+}
+
+/// Pick a free vector from [`DYNAMIC_VECTORS`] and install `handler` on it.
+pub fn allocate_vector(handler: IrqHandler, controller: IrqController) -> Result<u8, &'static str> {
+    DYNAMIC_VECTORS
+        .into_iter()
+        .find(|&vector| register_irq(vector, handler, controller).is_ok())
+        .ok_or("no free interrupt vectors")
+}
+
+/// Remove the handler from `vector`; later interrupts on it are reported as
+/// unexpected.
+pub fn unregister_irq(vector: u8) -> Result<(), &'static str> {
+    check_irq_vector(vector)?;
+    IRQ_HANDLERS[vector as usize].store(0, Ordering::SeqCst);
+    Ok(())
+}
+
+/// How often `vector` fired since boot, on all cores together.
+pub fn irq_count(vector: u8) -> u64 {
+    IRQ_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+fn end_of_interrupt(vector: u8, controller: IrqController) {
+    match controller {
+        IrqController::Pic => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+        IrqController::Apic => apic::end_of_interrupt(),
+    }
+}
+
+/// Entry for every vector in `FIRST_IRQ_VECTOR..SPURIOUS_VECTOR`, called from
+/// the shared register-saving stub. A vector without a handler is
+/// acknowledged at the local APIC.
+pub(crate) fn dispatch_irq(vector: u8) {
+    IRQ_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    percpu::this_cpu().stats.interrupts.fetch_add(1, Ordering::Relaxed);
+
+    let (handler, controller) = unpack(IRQ_HANDLERS[vector as usize].load(Ordering::Acquire));
+    match handler {
+        Some(handler) => handler(vector),
+        None => {
+            serial_println!("unexpected interrupt on vector {:#x}", vector);
+        }
+    }
+    end_of_interrupt(vector, controller);
+}
+
+const IRQ_STUB_COUNT: usize = (SPURIOUS_VECTOR - FIRST_IRQ_VECTOR) as usize;
+const IRQ_STUB_SIZE: u64 = 16;
+
+/// One 16-byte stub per IRQ vector, each pushing a zero error code and its
+/// vector before joining the exception path, which saves the registers and
+/// calls [`dispatch_irq`].
+#[unsafe(naked)]
+extern "C" fn irq_stubs() {
+    naked_asm!(
+        ".align 16",
+        ".set irq_stub_vector, {first}",
+        ".rept {count}",
+        ".align 16",
+        "push 0",
+        "push irq_stub_vector",
+        "jmp {common}",
+        ".set irq_stub_vector, irq_stub_vector + 1",
+        ".endr",
+        first = const FIRST_IRQ_VECTOR,
+        count = const IRQ_STUB_COUNT,
+        common = sym exception::exception_common,
+    );
+}
+
+fn install_irq_stubs(idt: &mut InterruptDescriptorTable) {
+    let first_stub = (irq_stubs as usize as u64).next_multiple_of(IRQ_STUB_SIZE);
+    for i in 0..IRQ_STUB_COUNT {
+        let addr = VirtAddr::new(first_stub + i as u64 * IRQ_STUB_SIZE);
+        unsafe { idt[FIRST_IRQ_VECTOR + i as u8].set_handler_addr(addr) };
+    }
+}
+
+/// Register the kernel's own fixed interrupt handlers and route the ISA
+/// devices to the calling CPU. Runs once on the BSP, after `ioapic::init`
+/// and before interrupts are enabled.
+pub fn init_irqs() {
+    let apic_id = apic::get_apic_id();
+    let isa_devices: [(u8, InterruptIndex, IrqHandler); 3] = [
+        (ioapic::ISA_KEYBOARD, InterruptIndex::Keyboard, keyboard_interrupt_handler),
+        (ioapic::ISA_COM1, InterruptIndex::Serial, serial_interrupt_handler),
+        (ioapic::ISA_RTC, InterruptIndex::Rtc, time::rtc::handle_interrupt),
+    ];
+    for (irq, index, handler) in isa_devices {
+        register_irq(index.as_u8(), handler, IrqController::Apic).expect("ISA vector taken");
+        if let Err(e) = ioapic::route_isa_irq(irq, index.as_u8(), apic_id) {
+            serial_println!("failed to route ISA IRQ {}: {}", irq, e);
+        }
+    }
+    time::rtc::init_interrupts();
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        exception::install(&mut idt);
+        install_irq_stubs(&mut idt);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
 
         unsafe {
@@ -42,8 +224,6 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
 
         idt
@@ -59,16 +239,27 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    hlt();
+/// Panic with a stack overflow report if `addr` hit one of the per-cpu stack guard pages.
+fn check_stack_overflow(addr: VirtAddr, stack_frame: &InterruptStackFrame) {
+    if let Some((cpu, stack)) = gdt::stack_guard_hit(addr) {
+        panic!(
+            "stack overflow on CPU {} ({} stack, accessed {:?})\n{:#?}",
+            cpu, stack, addr, stack_frame
+        );
+    }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    FPS_COUNTER.lock().tick();
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    use x86_64::registers::control::Cr2;
+
+    // A fault on a guard page can't push its frame onto the overflowed stack,
+    // so overflows escalate to here (on the IST stack) with CR2 still set.
+    if let Ok(addr) = Cr2::read() {
+        check_stack_overflow(addr, &stack_frame);
+    }
 
-    // Use APIC EOI instead of PIC
-    apic::end_of_interrupt();
+    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    hlt();
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -77,17 +268,33 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let addr = Cr2::read();
+    if let Ok(addr) = addr {
+        if memory::demand::handle_page_fault(addr, error_code) {
+            return;
+        }
+        check_stack_overflow(addr, &stack_frame);
+    }
 
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
+        addr, error_code, stack_frame
+    );
+}
+
+fn serial_interrupt_handler(_vector: u8) {
+    // the UART raises this for received data; echo it like keyboard input.
+    // Only read while the line status says a byte is waiting, so a stray or
+    // already drained interrupt can't spin here.
     loop {
-        hlt();
+        let Ok(byte) = crate::serial::SERIAL.lock().try_receive() else {
+            break;
+        };
+        print!("{}", byte as char);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler(_vector: u8) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -113,10 +320,4 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             }
         }
     }
-
-    // Use PIC EOI for keyboard (keep keyboard on PIC)
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
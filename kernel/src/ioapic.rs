@@ -0,0 +1,212 @@
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::PhysAddr;
+
+use crate::acpi::{self, IoApicInfo, Polarity, TriggerMode};
+use crate::interrupt::PICS;
+use crate::memory::mmio::{map_mmio, CacheType, MmioRegion};
+use crate::serial_log;
+
+/// Where the I/O APIC lives when there is no MADT to ask.
+const DEFAULT_IOAPIC_ADDRESS: u64 = 0xFEC0_0000;
+
+// ISA IRQ lines
+pub const ISA_KEYBOARD: u8 = 1;
+pub const ISA_COM1: u8 = 4;
+pub const ISA_RTC: u8 = 8;
+
+// register select / data window
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_MMIO_SIZE: usize = 0x20;
+
+// registers
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// redirection entry bits
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 14;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+
+struct IoApic {
+    mmio: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(info: &IoApicInfo) -> Result<Self, &'static str> {
+        let mmio = unsafe { map_mmio(info.address, IOAPIC_MMIO_SIZE, CacheType::Uncached)? };
+        let mut ioapic = Self {
+            mmio,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        ioapic.entries = ((ioapic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        Ok(ioapic)
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        self.mmio.write(IOREGSEL, reg);
+        self.mmio.read(IOWIN)
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        self.mmio.write(IOREGSEL, reg);
+        self.mmio.write(IOWIN, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn read_entry(&self, index: u32) -> u64 {
+        let low = self.read(IOREDTBL + 2 * index) as u64;
+        let high = self.read(IOREDTBL + 2 * index + 1) as u64;
+        (high << 32) | low
+    }
+
+    fn write_entry(&self, index: u32, entry: u64) {
+        // mask first so the entry is never live half written
+        self.write(IOREDTBL + 2 * index, REDIR_MASKED as u32);
+        self.write(IOREDTBL + 2 * index + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + 2 * index, entry as u32);
+    }
+}
+
+static IO_APICS: Once<Vec<Mutex<IoApic>>> = Once::new();
+
+/// Run `f` on the I/O APIC that owns `gsi` and the entry index inside it.
+fn with_gsi<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, &'static str> {
+    let ioapics = IO_APICS.get().ok_or("I/O APIC not initialized")?;
+    interrupts::without_interrupts(|| {
+        ioapics
+            .iter()
+            .map(|ioapic| ioapic.lock())
+            .find(|ioapic| ioapic.handles(gsi))
+            .map(|ioapic| f(&ioapic, gsi - ioapic.gsi_base))
+            .ok_or("no I/O APIC handles this GSI")
+    })
+}
+
+/// Whether some I/O APIC has an input for `gsi`.
+pub fn has_gsi(gsi: u32) -> bool {
+    with_gsi(gsi, |_, _| ()).is_ok()
+}
+
+/// Remap the 8259 pair away from the exception vectors and mask every line,
+/// so the only interrupts left are the ones routed through the I/O APIC.
+fn disable_pics() {
+    unsafe {
+        PICS.lock().initialize();
+        Port::<u8>::new(0x21).write(0xFF);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
+}
+
+/// Map every I/O APIC from the MADT, mask all their inputs and retire the
+/// legacy PICs. Runs once on the BSP.
+pub fn init() {
+    disable_pics();
+
+    IO_APICS.call_once(|| {
+        let fallback = [IoApicInfo {
+            id: 0,
+            address: PhysAddr::new(DEFAULT_IOAPIC_ADDRESS),
+            gsi_base: 0,
+        }];
+        let infos = match acpi::madt() {
+            Some(madt) if !madt.io_apics.is_empty() => &madt.io_apics[..],
+            _ => {
+                serial_log!("I/O APIC: no MADT entry, assuming one at {:#x}", DEFAULT_IOAPIC_ADDRESS);
+                &fallback[..]
+            }
+        };
+
+        infos
+            .iter()
+            .filter_map(|info| match IoApic::new(info) {
+                Ok(ioapic) => Some(ioapic),
+                Err(e) => {
+                    serial_log!("I/O APIC {}: {}", info.id, e);
+                    None
+                }
+            })
+            .inspect(|ioapic| {
+                for index in 0..ioapic.entries {
+                    ioapic.write_entry(index, REDIR_MASKED);
+                }
+                serial_log!(
+                    "I/O APIC {}: GSIs {}..{}",
+                    ioapic.read(IOAPICID) >> 24,
+                    ioapic.gsi_base,
+                    ioapic.gsi_base + ioapic.entries
+                );
+            })
+            .map(Mutex::new)
+            .collect()
+    });
+}
+
+/// Deliver global system interrupt `gsi` as `vector` to the local APIC with
+/// ID `apic_id`.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    apic_id: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+) -> Result<(), &'static str> {
+    if apic_id > 0xFF {
+        return Err("APIC ID does not fit an I/O APIC destination");
+    }
+    let mut entry = vector as u64 | ((apic_id as u64) << REDIR_DEST_SHIFT);
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIR_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= REDIR_LEVEL;
+    }
+    with_gsi(gsi, |ioapic, index| ioapic.write_entry(index, entry))
+}
+
+/// GSI, polarity and trigger mode an ISA IRQ is wired to, after applying
+/// the MADT interrupt source overrides.
+pub fn isa_irq_to_gsi(irq: u8) -> (u32, Polarity, TriggerMode) {
+    acpi::madt()
+        .and_then(|madt| madt.overrides.iter().find(|o| o.isa_irq == irq))
+        .map_or((irq as u32, Polarity::ActiveHigh, TriggerMode::Edge), |o| {
+            (o.gsi, o.polarity, o.trigger)
+        })
+}
+
+/// Route ISA IRQ `irq` to `vector` on the CPU with local APIC ID `apic_id`.
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) -> Result<(), &'static str> {
+    let (gsi, polarity, trigger) = isa_irq_to_gsi(irq);
+    route_gsi(gsi, vector, apic_id, polarity, trigger)
+}
+
+/// Send an already routed GSI to a different CPU.
+pub fn set_gsi_destination(gsi: u32, apic_id: u32) -> Result<(), &'static str> {
+    if apic_id > 0xFF {
+        return Err("APIC ID does not fit an I/O APIC destination");
+    }
+    with_gsi(gsi, |ioapic, index| {
+        let entry = ioapic.read_entry(index) & !(0xFF << REDIR_DEST_SHIFT);
+        ioapic.write_entry(index, entry | ((apic_id as u64) << REDIR_DEST_SHIFT));
+    })
+}
+
+/// Mask or unmask a GSI without touching the rest of its routing.
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> Result<(), &'static str> {
+    with_gsi(gsi, |ioapic, index| {
+        let entry = ioapic.read_entry(index);
+        let entry = if masked { entry | REDIR_MASKED } else { entry & !REDIR_MASKED };
+        ioapic.write_entry(index, entry);
+    })
+}
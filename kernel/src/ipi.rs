@@ -0,0 +1,185 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::apic;
+use crate::boot::boot_info;
+use crate::interrupt::{register_irq, IrqController, IrqHandler};
+use crate::percpu::this_cpu;
+use crate::time::{Duration, Instant};
+
+pub use crate::apic::{send_ipi, IpiDestination, IpiKind};
+
+/// Vectors above the dynamically allocated range, kept for inter-processor
+/// interrupts.
+pub const IPI_VECTORS: RangeInclusive<u8> = 0xF0..=0xFE;
+
+/// Runs the function published by the `call_on_*` family.
+pub const CALL_FUNCTION_VECTOR: u8 = 0xF0;
+
+/// A function other cores are asked to run, living on the caller's stack
+/// until every target has acknowledged it.
+struct CallRequest<'a> {
+    func: &'a (dyn Fn() + Sync),
+    remaining: AtomicUsize,
+}
+
+static CALL_REQUEST: AtomicPtr<CallRequest<'static>> = AtomicPtr::new(ptr::null_mut());
+
+/// A target that takes longer than this to run a cross call is assumed hung.
+const CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Only one cross call is in flight at a time.
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Per CPU (indexed like `BootInfo::cpus`): APIC enabled and IDT loaded, so
+/// an IPI will be taken (once it enables interrupts) rather than lost.
+static READY: Once<Vec<AtomicBool>> = Once::new();
+
+/// Set up the IPI bookkeeping and the cross call handler. Runs once on the
+/// BSP, before any AP is started.
+pub fn init() {
+    READY.call_once(|| boot_info().cpus.iter().map(|_| AtomicBool::new(false)).collect());
+    register_irq(CALL_FUNCTION_VECTOR, call_function_handler, IrqController::Apic)
+        .expect("call function vector taken");
+}
+
+fn ready() -> &'static [AtomicBool] {
+    READY.get().expect("IPIs not initialized")
+}
+
+/// Mark CPU `cpu_index` as able to receive IPIs. Call once its local APIC is
+/// enabled and its IDT loaded; interrupts may still be off.
+pub fn mark_ready(cpu_index: usize) {
+    ready()[cpu_index].store(true, Ordering::SeqCst);
+}
+
+/// Whether CPU `cpu_index` takes part in cross calls.
+pub fn is_ready(cpu_index: usize) -> bool {
+    READY
+        .get()
+        .and_then(|ready| ready.get(cpu_index))
+        .is_some_and(|r| r.load(Ordering::SeqCst))
+}
+
+/// Whether any CPU other than the caller takes cross calls, so that
+/// [`call_on_others`] needs interrupts enabled.
+pub fn others_ready() -> bool {
+    let Some(ready) = READY.get() else {
+        return false;
+    };
+    let own = apic::get_apic_id();
+    boot_info()
+        .cpus
+        .iter()
+        .zip(ready)
+        .any(|(cpu, ready)| cpu.lapic_id != own && ready.load(Ordering::SeqCst))
+}
+
+/// Install `handler` on a free vector from [`IPI_VECTORS`], to be raised
+/// with [`IpiKind::Fixed`].
+pub fn register_ipi_handler(handler: IrqHandler) -> Result<u8, &'static str> {
+    IPI_VECTORS
+        .into_iter()
+        .find(|&vector| register_irq(vector, handler, IrqController::Apic).is_ok())
+        .ok_or("no free IPI vectors")
+}
+
+fn call_function_handler(_vector: u8) {
+    let request = CALL_REQUEST.load(Ordering::Acquire);
+    if request.is_null() {
+        return;
+    }
+    // the caller keeps the request alive until `remaining` drops to zero
+    let request = unsafe { &*request };
+    (request.func)();
+    this_cpu().stats.cross_calls.fetch_add(1, Ordering::Relaxed);
+    request.remaining.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Run `func` on every CPU whose local APIC ID is in `targets` and wait for
+/// all of them to finish.
+fn call_on_apics(targets: &[u32], func: &(dyn Fn() + Sync)) {
+    if targets.is_empty() {
+        return;
+    }
+    // a caller spinning with interrupts off could never answer a concurrent
+    // cross call aimed at it
+    assert!(interrupts::are_enabled(), "cross call with interrupts disabled");
+
+    let _guard = CALL_LOCK.lock();
+    let request = CallRequest { func, remaining: AtomicUsize::new(targets.len()) };
+    CALL_REQUEST.store(&request as *const CallRequest as *mut CallRequest<'static>, Ordering::Release);
+
+    for &apic_id in targets {
+        send_ipi(IpiDestination::Apic(apic_id), IpiKind::Fixed(CALL_FUNCTION_VECTOR))
+            .expect("CPU from boot info is not addressable");
+    }
+    let deadline = Instant::now() + CALL_TIMEOUT;
+    while request.remaining.load(Ordering::Acquire) != 0 {
+        if Instant::now() > deadline {
+            panic!("{} CPU(s) did not answer a cross call", request.remaining.load(Ordering::Acquire));
+        }
+        core::hint::spin_loop();
+    }
+    CALL_REQUEST.store(ptr::null_mut(), Ordering::Release);
+}
+
+/// Local APIC IDs of every ready CPU other than the caller.
+fn other_ready_apics() -> Vec<u32> {
+    if READY.get().is_none() {
+        return Vec::new();
+    }
+    let own = apic::get_apic_id();
+    boot_info()
+        .cpus
+        .iter()
+        .enumerate()
+        .filter(|&(i, cpu)| cpu.lapic_id != own && is_ready(i))
+        .map(|(_, cpu)| cpu.lapic_id)
+        .collect()
+}
+
+/// Run `func` on CPU `cpu_index` (the caller's own included) and wait for
+/// it to return. Needs interrupts enabled.
+pub fn call_on_cpu(cpu_index: usize, func: &(dyn Fn() + Sync)) -> Result<(), &'static str> {
+    let cpu = boot_info().cpus.get(cpu_index).ok_or("no such CPU")?;
+    if cpu.lapic_id == apic::get_apic_id() {
+        func();
+        return Ok(());
+    }
+    if !is_ready(cpu_index) {
+        return Err("CPU does not take IPIs yet");
+    }
+    call_on_apics(&[cpu.lapic_id], func);
+    Ok(())
+}
+
+/// Run `func` on every other ready CPU and wait until all of them are done.
+/// Needs interrupts enabled unless no other CPU is ready yet.
+pub fn call_on_others(func: &(dyn Fn() + Sync)) {
+    call_on_apics(&other_ready_apics(), func);
+}
+
+/// Run `func` on every ready CPU, the caller last.
+pub fn call_on_all(func: &(dyn Fn() + Sync)) {
+    call_on_others(func);
+    func();
+}
+
+/// Halt CPU `cpu_index` with an INIT IPI, whatever it is doing. It drops out
+/// of cross calls and can only come back through a fresh startup sequence.
+pub fn stop_cpu(cpu_index: usize) -> Result<(), &'static str> {
+    let cpu = boot_info().cpus.get(cpu_index).ok_or("no such CPU")?;
+    if cpu.lapic_id == apic::get_apic_id() {
+        return Err("a CPU cannot stop itself");
+    }
+    // hold the lock so no cross call waits on a CPU that is going away
+    let _guard = CALL_LOCK.lock();
+    ready()[cpu_index].store(false, Ordering::SeqCst);
+    send_ipi(IpiDestination::Apic(cpu.lapic_id), IpiKind::Init)
+}
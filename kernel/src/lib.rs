@@ -1,15 +1,22 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod boot;
+pub mod exception;
 pub mod framebuffer;
 pub mod gdt;
 pub mod interrupt;
+pub mod ioapic;
+pub mod ipi;
 pub mod memory;
+pub mod percpu;
 pub mod serial;
+pub mod time;
 pub mod apic;
 mod tests;
 
@@ -43,29 +50,116 @@ pub fn lib_main() -> ! {
     }
 }
 
-fn tests() -> &'static [(&'static str, fn())] {
+/// Whether a test may leave heap allocations behind.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Leaks {
+    Forbidden,
+    Allowed,
+}
+
+fn tests() -> &'static [(&'static str, fn(), Leaks)] {
+    use tests::acpi::test_acpi_tables;
     use tests::framebuffer::test_println;
     use tests::framebuffer::test_screen;
-    use tests::heap::test_heap_allocations;
+    use tests::heap::{
+        test_heap_allocations, test_heap_growth, test_heap_stats, test_slab_size_classes,
+    };
+    use tests::interrupt::{
+        test_apic_id, test_cpu_count_sizing, test_cpu_numbering, test_exception_error_codes,
+        test_ioapic_routing, test_ipi_cross_call, test_irq_registration, test_nmi, test_percpu,
+    };
+    use tests::memory::{
+        test_frame_allocator, test_kernel_page_permissions, test_mmio_mapping,
+        test_stack_guard_pages, test_bootloader_memory_reclaimed, test_dma_buffer,
+        test_vmalloc, test_demand_paging, test_tlb_shootdown,
+    };
+    use tests::time::{
+        test_hpet_and_calibration, test_hpet_comparator, test_monotonic_clock, test_timers,
+        test_rtc_and_wall_clock,
+    };
     use tests::trivial_assertion;
 
     &[
-        ("trivial_assertion", trivial_assertion),
-        ("test_heap_allocations", test_heap_allocations),
-        ("test_println", test_println),
-        ("test_screen", test_screen),
+        ("trivial_assertion", trivial_assertion, Leaks::Forbidden),
+        ("test_heap_allocations", test_heap_allocations, Leaks::Forbidden),
+        ("test_heap_growth", test_heap_growth, Leaks::Forbidden),
+        ("test_slab_size_classes", test_slab_size_classes, Leaks::Forbidden),
+        ("test_heap_stats", test_heap_stats, Leaks::Forbidden),
+        ("test_frame_allocator", test_frame_allocator, Leaks::Forbidden),
+        ("test_kernel_page_permissions", test_kernel_page_permissions, Leaks::Forbidden),
+        ("test_mmio_mapping", test_mmio_mapping, Leaks::Forbidden),
+        ("test_stack_guard_pages", test_stack_guard_pages, Leaks::Forbidden),
+        ("test_bootloader_memory_reclaimed", test_bootloader_memory_reclaimed, Leaks::Forbidden),
+        ("test_dma_buffer", test_dma_buffer, Leaks::Forbidden),
+        ("test_vmalloc", test_vmalloc, Leaks::Forbidden),
+        ("test_demand_paging", test_demand_paging, Leaks::Forbidden),
+        ("test_tlb_shootdown", test_tlb_shootdown, Leaks::Forbidden),
+        ("test_exception_error_codes", test_exception_error_codes, Leaks::Forbidden),
+        ("test_irq_registration", test_irq_registration, Leaks::Forbidden),
+        ("test_ioapic_routing", test_ioapic_routing, Leaks::Forbidden),
+        ("test_apic_id", test_apic_id, Leaks::Forbidden),
+        ("test_ipi_cross_call", test_ipi_cross_call, Leaks::Forbidden),
+        ("test_nmi", test_nmi, Leaks::Forbidden),
+        ("test_percpu", test_percpu, Leaks::Forbidden),
+        ("test_cpu_numbering", test_cpu_numbering, Leaks::Forbidden),
+        ("test_cpu_count_sizing", test_cpu_count_sizing, Leaks::Forbidden),
+        ("test_acpi_tables", test_acpi_tables, Leaks::Forbidden),
+        ("test_hpet_and_calibration", test_hpet_and_calibration, Leaks::Forbidden),
+        ("test_hpet_comparator", test_hpet_comparator, Leaks::Forbidden),
+        ("test_monotonic_clock", test_monotonic_clock, Leaks::Forbidden),
+        ("test_timers", test_timers, Leaks::Forbidden),
+        ("test_rtc_and_wall_clock", test_rtc_and_wall_clock, Leaks::Forbidden),
+        ("test_println", test_println, Leaks::Forbidden),
+        ("test_screen", test_screen, Leaks::Allowed),
     ]
 }
 
 pub fn test_main() {
-    for (name, f) in tests() {
+    for &(name, f, leaks) in tests() {
         serial_print!("{}... ", name);
+        let before = memory::heap::stats();
         f();
+        let after = memory::heap::stats();
+        if leaks == Leaks::Forbidden {
+            check_leaks(name, &before, &after);
+        }
         serial_println!("[ok]");
     }
     exit_qemu(QemuExitCode::Success)
 }
 
+/// Fail the running test if it returned holding more allocations or more
+/// bytes than it started with; the latter catches a leak hidden by a
+/// smaller allocation the test freed.
+///
+/// The counters are global, so anything the APs, interrupt handlers or timer
+/// callbacks allocate while the test runs counts against it too. Tests that
+/// leave such work running past their return must use [`Leaks::Allowed`].
+fn check_leaks(name: &str, before: &memory::heap::HeapStats, after: &memory::heap::HeapStats) {
+    use memory::heap::{HeapStats, HISTOGRAM_BUCKETS};
+
+    if after.live_allocations <= before.live_allocations && after.allocated <= before.allocated {
+        return;
+    }
+    serial_println!(
+        "[leak] {} allocations, {} bytes",
+        after.live_allocations.saturating_sub(before.live_allocations),
+        after.allocated.saturating_sub(before.allocated)
+    );
+    for bucket in 0..HISTOGRAM_BUCKETS {
+        let leaked = after.histogram[bucket].saturating_sub(before.histogram[bucket]);
+        if leaked == 0 {
+            continue;
+        }
+        if let Some(limit) = HeapStats::bucket_limit(bucket) {
+            serial_println!("  <= {} bytes: {}", limit, leaked);
+        } else {
+            serial_println!("  larger: {}", leaked);
+        }
+    }
+    panic!("{} leaked heap memory", name);
+}
+
 #[cfg(not(feature = "qemu_test"))]
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
@@ -3,11 +3,14 @@
 
 extern crate alloc;
 
-use kernel::{framebuffer::screen::{tv, SCREEN}, println};
+use kernel::{framebuffer::screen::{tv, SCREEN}, println, time};
 
 #[unsafe(no_mangle)]
 pub extern "C" fn kernel_main() -> ! {
     println!("FPS: 0");
+    if let Some(now) = time::wall_clock_now() {
+        println!("Booted {}", now);
+    }
 
 
     let mut screen = SCREEN.lock();
@@ -0,0 +1,112 @@
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::{frame, paging, phys_to_virt};
+
+/// How many lazily backed regions can be registered at once. Fixed so the
+/// page fault path never touches the heap.
+pub const MAX_LAZY_REGIONS: usize = 32;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Clone)]
+struct LazyRegion {
+    range: Range<u64>,
+    flags: PageTableFlags,
+}
+
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([const { None }; MAX_LAZY_REGIONS]);
+
+/// Register `start..start + size` as lazily backed: the first touch of each
+/// page maps a zeroed frame with `flags` instead of faulting.
+///
+/// The range must be page aligned, reserved by the caller (e.g. from a
+/// virtual region allocator) and not overlap another lazy region.
+pub fn register_lazy_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), &'static str> {
+    if !start.is_aligned(PAGE_SIZE) || size == 0 || !size.is_multiple_of(PAGE_SIZE) {
+        return Err("lazy region must be whole pages");
+    }
+    let range = start.as_u64()..start.as_u64() + size;
+
+    interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let overlaps = regions
+            .iter()
+            .flatten()
+            .any(|r| r.range.start < range.end && range.start < r.range.end);
+        if overlaps {
+            return Err("lazy region overlaps an existing one");
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many lazy regions")?;
+        *slot = Some(LazyRegion { range, flags });
+        Ok(())
+    })
+}
+
+/// Stop backing the region starting at `start` on demand. Pages that were
+/// already faulted in stay mapped; unmapping them is up to the caller.
+pub fn unregister_lazy_region(start: VirtAddr) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.as_ref().is_some_and(|r| r.range.start == start.as_u64()))
+            .ok_or("no lazy region starts there")?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Try to resolve a page fault at `addr` by backing a lazy region. Returns
+/// `false` if the fault is not ours to handle.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // only not-present faults are candidates; protection violations are bugs
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    let flags = {
+        let regions = LAZY_REGIONS.lock();
+        let region = regions.iter().flatten().find(|r| r.range.contains(&addr.as_u64()));
+        match region {
+            Some(region) => region.flags,
+            None => return false,
+        }
+    };
+
+    let Some(frame) = frame::allocate_frame() else {
+        return false;
+    };
+    unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, PAGE_SIZE as usize) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { paging::map_page(page, frame, flags) } {
+        Ok(()) => true,
+        // another core faulted on the same page and won the race
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            unsafe { frame::deallocate_frame(frame) };
+            true
+        }
+        Err(_) => {
+            unsafe { frame::deallocate_frame(frame) };
+            false
+        }
+    }
+}
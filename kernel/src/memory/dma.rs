@@ -0,0 +1,152 @@
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+use super::{frame, phys_to_virt};
+
+/// Highest physical address (exclusive) a 32-bit DMA engine can reach.
+pub const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Placement requirements for a DMA buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// Alignment of the physical start address in bytes. Rounded up to a
+    /// whole frame; must be a power of two.
+    pub align: u64,
+    /// Keep the whole buffer below 4 GiB, for devices with 32-bit addressing.
+    pub below_4gib: bool,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            align: frame::FRAME_SIZE,
+            below_4gib: false,
+        }
+    }
+}
+
+/// Physically contiguous, zero-initialised memory for `len` values of `T`
+/// that a device can access directly.
+///
+/// The CPU sees the buffer through the HHDM (write-back, which is coherent
+/// with DMA on x86), the device through [`phys_addr`](Self::phys_addr). The
+/// frames go back to the frame allocator when the handle is dropped, so the
+/// device must be done with the buffer by then.
+pub struct DmaBuffer<T> {
+    ptr: NonNull<T>,
+    phys: PhysAddr,
+    len: usize,
+    frames: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+unsafe impl<T: Sync> Sync for DmaBuffer<T> {}
+
+impl<T: Copy> DmaBuffer<T> {
+    /// Allocate room for `len` values, each initialised to `value`.
+    pub fn new(len: usize, value: T, constraints: DmaConstraints) -> Result<Self, &'static str> {
+        let bytes = len
+            .checked_mul(size_of::<T>())
+            .ok_or("DMA buffer size overflows")?
+            .max(1) as u64;
+        let frames = bytes.div_ceil(frame::FRAME_SIZE) as usize;
+
+        if !constraints.align.is_power_of_two() {
+            return Err("DMA alignment must be a power of two");
+        }
+        let align = constraints.align.max(frame::FRAME_SIZE) / frame::FRAME_SIZE;
+
+        let start = if constraints.below_4gib {
+            frame::allocate_contiguous_below(frames, align as usize, PhysAddr::new(DMA32_LIMIT))
+        } else {
+            frame::allocate_contiguous(frames, align as usize)
+        }
+        .ok_or("no physically contiguous memory for DMA buffer")?;
+
+        let phys = start.start_address();
+        let ptr = phys_to_virt(phys).as_mut_ptr::<T>();
+        unsafe {
+            ptr.cast::<u8>().write_bytes(0, frames * frame::FRAME_SIZE as usize);
+            for i in 0..len {
+                ptr.add(i).write(value);
+            }
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr).expect("HHDM mapped a frame at null"),
+            phys,
+            len,
+            frames,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> DmaBuffer<T> {
+    /// Address the device should be programmed with.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Physical address of element `index`.
+    pub fn phys_addr_of(&self, index: usize) -> Result<PhysAddr, &'static str> {
+        if index >= self.len {
+            return Err("DMA buffer index out of bounds");
+        }
+        Ok(self.phys + (index * size_of::<T>()) as u64)
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ptr.as_ptr())
+    }
+
+    /// Number of elements.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the backing allocation in bytes (whole frames).
+    pub fn capacity_bytes(&self) -> usize {
+        self.frames * frame::FRAME_SIZE as usize
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        // elements are `Copy`, so there is nothing to drop in place
+        unsafe { frame::deallocate_contiguous(PhysFrame::containing_address(self.phys), self.frames) };
+    }
+}
+
+impl<T> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("phys", &self.phys)
+            .field("virt", &self.virt_addr())
+            .field("len", &self.len)
+            .finish()
+    }
+}
@@ -0,0 +1,305 @@
+use limine::{memory_map::EntryType, response::MemoryMapResponse};
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use super::phys_to_virt;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+const BITS_PER_WORD: usize = 64;
+
+/// Physical frame allocator backed by a bitmap with one bit per 4 KiB frame
+/// (set = in use). The bitmap itself lives in the first usable region large
+/// enough to hold it and is accessed through the HHDM.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    total_frames: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    next_hint: usize,
+}
+
+/// Snapshot of the frame allocator counters.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub usable_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build the allocator from the Limine memory map.
+    ///
+    /// # Safety
+    /// The HHDM offset must be initialized and the USABLE entries of
+    /// `memory_map` must really be unused.
+    unsafe fn new(memory_map: &MemoryMapResponse) -> Self {
+        let entries = memory_map.entries();
+
+        // cover bootloader-reclaimable memory too so it can be added later
+        let highest = entries
+            .iter()
+            .filter(|r| {
+                r.entry_type == EntryType::USABLE
+                    || r.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+            })
+            .map(|r| r.base + r.length)
+            .max()
+            .expect("memory map has no usable regions");
+
+        let total_frames = (highest / FRAME_SIZE) as usize;
+        let words = total_frames.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (words * 8) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        // put the bitmap at the start of the first usable region that fits it
+        let bitmap_base = entries
+            .iter()
+            .filter(|r| r.entry_type == EntryType::USABLE)
+            .find(|r| r.length >= bitmap_frames * FRAME_SIZE)
+            .map(|r| r.base)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap = unsafe {
+            let ptr = phys_to_virt(PhysAddr::new(bitmap_base)).as_mut_ptr::<u64>();
+            core::slice::from_raw_parts_mut(ptr, words)
+        };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            total_frames,
+            usable_frames: 0,
+            free_frames: 0,
+            next_hint: 0,
+        };
+
+        for region in entries.iter().filter(|r| r.entry_type == EntryType::USABLE) {
+            allocator.add_region(region.base, region.length);
+        }
+
+        // frame 0 stays reserved so a null physical address is never handed out
+        if !allocator.test_bit(0) {
+            allocator.set_bit(0);
+            allocator.usable_frames -= 1;
+            allocator.free_frames -= 1;
+        }
+
+        let bitmap_start = (bitmap_base / FRAME_SIZE) as usize;
+        for index in bitmap_start..bitmap_start + bitmap_frames as usize {
+            allocator.set_bit(index);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    /// Mark the whole frames inside `base..base + length` as usable and free.
+    /// Frames outside the bitmap or already free are skipped.
+    fn add_region(&mut self, base: u64, length: u64) {
+        let start = base.div_ceil(FRAME_SIZE) as usize;
+        let end = (((base + length) / FRAME_SIZE) as usize).min(self.total_frames);
+        for index in start..end {
+            if self.test_bit(index) {
+                self.clear_bit(index);
+                self.usable_frames += 1;
+                self.free_frames += 1;
+            }
+        }
+        self.next_hint = self.next_hint.min(start);
+    }
+
+    #[inline]
+    fn test_bit(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    #[inline]
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    #[inline]
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Allocate a single frame, scanning a word at a time from the last hit.
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let words = self.bitmap.len();
+        let start_word = self.next_hint / BITS_PER_WORD;
+        for i in 0..words {
+            let word_index = (start_word + i) % words;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if index >= self.total_frames {
+                continue;
+            }
+            self.set_bit(index);
+            self.free_frames -= 1;
+            self.next_hint = index + 1;
+            return Some(Self::frame_at(index));
+        }
+        None
+    }
+
+    /// Allocate `count` physically contiguous frames whose first frame is
+    /// aligned to `align` frames. Returns the first frame of the run.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_below(count, align, self.total_frames)
+    }
+
+    /// Like [`allocate_contiguous`](Self::allocate_contiguous), but the whole
+    /// run has to end at or before frame index `limit`.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: usize,
+    ) -> Option<PhysFrame> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let align = align.max(1);
+        let limit = limit.min(self.total_frames);
+
+        let mut start = 0;
+        while start + count <= limit {
+            match (start..start + count).rev().find(|&i| self.test_bit(i)) {
+                // restart the search just past the frame that is in use
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.free_frames -= count;
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Return a single frame to the allocator.
+    pub fn deallocate(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1);
+    }
+
+    /// Return `count` frames starting at `start` to the allocator.
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = Self::index_of(start);
+        assert!(first + count <= self.total_frames, "freeing frame outside of managed memory");
+
+        for index in first..first + count {
+            assert!(self.test_bit(index), "double free of frame {:#x}", index as u64 * FRAME_SIZE);
+            self.clear_bit(index);
+        }
+        self.free_frames += count;
+        self.next_hint = self.next_hint.min(first);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            usable_frames: self.usable_frames,
+            free_frames: self.free_frames,
+            used_frames: self.usable_frames - self.free_frames,
+        }
+    }
+}
+
+static FRAME_ALLOCATOR: Once<Mutex<BitmapFrameAllocator>> = Once::new();
+
+/// Initialize the global frame allocator from the Limine memory map.
+///
+/// # Safety
+/// Must be called once, after `memory::init`, with the memory map Limine
+/// handed to us.
+pub unsafe fn init(memory_map: &MemoryMapResponse) {
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(unsafe { BitmapFrameAllocator::new(memory_map) }));
+}
+
+/// Run `f` with the global frame allocator locked and interrupts disabled,
+/// so an interrupt handler on the same core can never deadlock on it.
+fn with_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    let allocator = FRAME_ALLOCATOR.get().expect("frame allocator not initialized");
+    interrupts::without_interrupts(|| f(&mut allocator.lock()))
+}
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    with_allocator(|a| a.allocate())
+}
+
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrame> {
+    with_allocator(|a| a.allocate_contiguous(count, align))
+}
+
+/// Allocate `count` contiguous frames, aligned to `align` frames, that all lie
+/// below the physical address `limit`.
+pub fn allocate_contiguous_below(count: usize, align: usize, limit: PhysAddr) -> Option<PhysFrame> {
+    let limit = (limit.as_u64() / FRAME_SIZE) as usize;
+    with_allocator(|a| a.allocate_contiguous_below(count, align, limit))
+}
+
+/// Return a frame to the global allocator.
+///
+/// # Safety
+/// `frame` must have come from this allocator and must no longer be mapped or
+/// otherwise in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    with_allocator(|a| a.deallocate(frame))
+}
+
+/// Return a run of frames to the global allocator.
+///
+/// # Safety
+/// See [`deallocate_frame`]; the whole run must be unused.
+pub unsafe fn deallocate_contiguous(start: PhysFrame, count: usize) {
+    with_allocator(|a| a.deallocate_contiguous(start, count))
+}
+
+/// Hand memory that was in use during boot (e.g. bootloader-reclaimable
+/// regions) over to the allocator.
+///
+/// # Safety
+/// Nothing may reference `base..base + length` anymore.
+pub unsafe fn add_region(base: u64, length: u64) {
+    with_allocator(|a| a.add_region(base, length))
+}
+
+pub fn stats() -> FrameStats {
+    with_allocator(|a| a.stats())
+}
+
+/// Zero-sized handle to the global frame allocator, for the `x86_64` paging APIs.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { deallocate_frame(frame) }
+    }
+}
@@ -1,40 +1,266 @@
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+use super::{frame::GlobalFrameAllocator, slab::SlabAllocator, with_mapper};
+use crate::serial_println;
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1000 * 1024; // 1000 KiB
+pub const HEAP_INITIAL_SIZE: usize = 1000 * 1024; // 1000 KiB
+/// Default ceiling the heap may grow to; change at runtime with [`set_max_size`].
+pub const HEAP_DEFAULT_MAX_SIZE: usize = 512 * 1024 * 1024; // 512 MiB
+
+/// Minimum amount the heap is grown by, so small allocations don't remap every time.
+const HEAP_GROW_STEP: usize = 256 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// Number of buckets in [`HeapStats::histogram`]. Bucket `i` counts live
+/// allocations of at most `16 << i` bytes, the last one everything larger.
+pub const HISTOGRAM_BUCKETS: usize = 16;
+
+static HEAP_MAX_SIZE: AtomicUsize = AtomicUsize::new(HEAP_DEFAULT_MAX_SIZE);
+
+/// Snapshot of the heap counters.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently mapped for the heap.
+    pub size: usize,
+    /// Bytes the backend has handed out, including slabs cached by the slab layer.
+    pub used: usize,
+    pub free: usize,
+    pub max_size: usize,
+    /// Bytes callers currently hold.
+    pub allocated: usize,
+    /// Highest value `allocated` has reached.
+    pub peak_allocated: usize,
+    pub live_allocations: usize,
+    /// Live allocations by size, see [`HISTOGRAM_BUCKETS`].
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl HeapStats {
+    /// Upper size bound of histogram bucket `bucket`, `None` for the last one.
+    pub fn bucket_limit(bucket: usize) -> Option<usize> {
+        (bucket + 1 < HISTOGRAM_BUCKETS).then(|| 16 << bucket)
+    }
+}
+
+/// Caller-visible allocation counters, kept by [`CountingAllocator`].
+struct AllocCounters {
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+    live: AtomicUsize,
+    histogram: [AtomicUsize; HISTOGRAM_BUCKETS],
+}
+
+impl AllocCounters {
+    const fn new() -> Self {
+        Self {
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            histogram: [const { AtomicUsize::new(0) }; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    fn bucket(size: usize) -> usize {
+        let order = size.max(16).next_power_of_two().trailing_zeros() as usize;
+        (order - 4).min(HISTOGRAM_BUCKETS - 1)
+    }
+
+    fn record_alloc(&self, size: usize) {
+        let allocated = self.allocated.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(allocated, Ordering::Relaxed);
+        self.live.fetch_add(1, Ordering::Relaxed);
+        self.histogram[Self::bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, size: usize) {
+        self.allocated.fetch_sub(size, Ordering::Relaxed);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.histogram[Self::bucket(size)].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Linked-list heap that maps more pages on demand when it runs out.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        Self {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        interrupts::without_interrupts(|| {
+            let heap = self.heap.lock();
+            HeapStats {
+                size: heap.size(),
+                used: heap.used(),
+                free: heap.free(),
+                max_size: HEAP_MAX_SIZE.load(Ordering::Relaxed),
+                allocated: 0,
+                peak_allocated: 0,
+                live_allocations: 0,
+                histogram: [0; HISTOGRAM_BUCKETS],
+            }
+        })
+    }
+
+    /// Map enough pages past the current top to satisfy `layout`.
+    fn grow(heap: &mut Heap, layout: Layout) -> Result<(), MapToError<Size4KiB>> {
+        let needed = layout.size() + layout.align();
+        let by = needed.max(HEAP_GROW_STEP).next_multiple_of(PAGE_SIZE);
+
+        let max_size = HEAP_MAX_SIZE.load(Ordering::Relaxed);
+        if heap.size() + by > max_size {
+            return Err(MapToError::FrameAllocationFailed);
+        }
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+        map_heap_pages(heap.top() as usize, by)?;
+        unsafe { heap.extend(by) };
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let mut heap = self.heap.lock();
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if Self::grow(&mut heap, layout).is_err() {
+                return ptr::null_mut();
+            }
+            heap.allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| unsafe {
+            self.heap
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout);
+        })
+    }
+}
+
+fn map_heap_pages(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size as u64 - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    with_mapper(|mapper| {
+        let frame_allocator = &mut GlobalFrameAllocator;
+        for page in page_range {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(())
+    })
+}
+
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_INITIAL_SIZE)?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        BACKEND
+            .heap
+            .lock()
+            .init(HEAP_START as *mut u8, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
 }
 
+/// Set up the per-CPU slab magazines for `num_cpus` CPUs. Runs once on the
+/// BSP; allocations keep working, through a shared magazine, until then.
+pub fn init_cpus(num_cpus: usize) {
+    ALLOCATOR.slab.init_cpus(num_cpus);
+}
+
+/// Change the ceiling the heap is allowed to grow to. Memory that is already
+/// mapped is never given back, so lowering it below the current size only
+/// stops further growth.
+pub fn set_max_size(bytes: usize) {
+    HEAP_MAX_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+pub fn stats() -> HeapStats {
+    let counters = &ALLOCATOR.counters;
+    HeapStats {
+        allocated: counters.allocated.load(Ordering::Relaxed),
+        peak_allocated: counters.peak.load(Ordering::Relaxed),
+        live_allocations: counters.live.load(Ordering::Relaxed),
+        histogram: core::array::from_fn(|i| counters.histogram[i].load(Ordering::Relaxed)),
+        ..ALLOCATOR.slab.backend().stats()
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = stats();
+    serial_println!(
+        "heap: allocation of {} bytes (align {}) failed; size {} / max {}, used {}, free {}",
+        layout.size(),
+        layout.align(),
+        stats.size,
+        stats.max_size,
+        stats.used,
+        stats.free
+    );
+    panic!("out of kernel heap memory for {:?}", layout);
+}
+
+/// Page-level backend: serves large allocations and the slabs small ones are cut from.
+static BACKEND: GrowableHeap = GrowableHeap::empty();
+
+/// Global allocator front end: counts what callers hold, then defers to the
+/// slab layer.
+struct CountingAllocator {
+    slab: SlabAllocator,
+    counters: AllocCounters,
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.slab.alloc(layout) };
+        if !ptr.is_null() {
+            self.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.counters.record_dealloc(layout.size());
+        unsafe { self.slab.dealloc(ptr, layout) }
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingAllocator = CountingAllocator {
+    slab: SlabAllocator::new(&BACKEND),
+    counters: AllocCounters::new(),
+};
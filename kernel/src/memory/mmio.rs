@@ -0,0 +1,187 @@
+use core::arch::asm;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{paging, tlb, vspace::VirtualRegionAllocator};
+
+/// Virtual window MMIO mappings are handed out from.
+pub const MMIO_WINDOW_START: u64 = 0xFFFF_D000_0000_0000;
+pub const MMIO_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+const PAGE_SIZE: u64 = 4096;
+
+const IA32_PAT: u32 = 0x277;
+
+// PAT memory type encodings
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// PAT layout: PA0 = WB, PA1 = WC, PA2 = WT, PA3 = UC, repeated for PA4-7.
+/// Every cache type is then reachable through PWT/PCD alone, so the PAT bit
+/// (which aliases HUGE_PAGE in a 4 KiB entry) is never needed.
+const PAT_VALUE: u64 = {
+    let low = PAT_WB | (PAT_WC << 8) | (PAT_WT << 16) | (PAT_UC << 24);
+    low | (low << 32)
+};
+
+/// Caching behaviour requested for an MMIO mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    Uncached,
+}
+
+impl CacheType {
+    /// PWT/PCD bits selecting this type under [`PAT_VALUE`].
+    fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::WriteThrough => PageTableFlags::NO_CACHE,
+            CacheType::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+lazy_static! {
+    static ref MMIO_SPACE: Mutex<VirtualRegionAllocator> = Mutex::new(
+        VirtualRegionAllocator::new(VirtAddr::new(MMIO_WINDOW_START), MMIO_WINDOW_SIZE)
+    );
+}
+
+/// Program this core's PAT with the kernel layout. Has to run on every core
+/// before it uses a non write-back mapping.
+pub fn init_pat() {
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // drop cache lines that may have been filled under the old types
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+}
+
+/// A device register range mapped into the MMIO window.
+///
+/// The mapping stays alive until passed to [`unmap_mmio`]; dropping the
+/// handle leaks it, which is what long-lived device mappings want.
+#[derive(Debug)]
+pub struct MmioRegion {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+
+    /// Volatile read of the register at `offset` bytes into the region.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.len, "MMIO read out of bounds");
+        unsafe { self.as_mut_ptr::<u8>().add(offset).cast::<T>().read_volatile() }
+    }
+
+    /// Volatile write of the register at `offset` bytes into the region.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.len, "MMIO write out of bounds");
+        unsafe { self.as_mut_ptr::<u8>().add(offset).cast::<T>().write_volatile(value) }
+    }
+
+    fn page_range(&self) -> (VirtAddr, u64) {
+        let start = self.virt.align_down(PAGE_SIZE);
+        let end = (self.virt + self.len as u64).align_up(PAGE_SIZE);
+        (start, end - start)
+    }
+}
+
+/// Map `len` bytes of physical address space at `phys` into the MMIO window
+/// with the given cache type.
+///
+/// # Safety
+/// `phys..phys + len` must be device memory (or otherwise not owned by the
+/// frame allocator), and the caller is responsible for accessing it with the
+/// semantics the device expects.
+pub unsafe fn map_mmio(
+    phys: PhysAddr,
+    len: usize,
+    cache_type: CacheType,
+) -> Result<MmioRegion, &'static str> {
+    if len == 0 {
+        return Err("empty MMIO mapping");
+    }
+
+    let phys_start = phys.align_down(PAGE_SIZE);
+    let offset = phys - phys_start;
+    let size = (offset + len as u64).next_multiple_of(PAGE_SIZE);
+
+    let virt_start = MMIO_SPACE
+        .lock()
+        .allocate(size, PAGE_SIZE)
+        .ok_or("MMIO window exhausted")?;
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache_type.page_flags();
+    for i in 0..size / PAGE_SIZE {
+        let page = Page::<Size4KiB>::containing_address(virt_start + i * PAGE_SIZE);
+        let frame = PhysFrame::containing_address(phys_start + i * PAGE_SIZE);
+        if unsafe { paging::map_page(page, frame, flags) }.is_err() {
+            // roll back what was mapped so far
+            for j in 0..i {
+                let page = Page::<Size4KiB>::containing_address(virt_start + j * PAGE_SIZE);
+                let _ = unsafe { paging::unmap_page(page) };
+            }
+            MMIO_SPACE.lock().free(virt_start, size);
+            return Err("failed to map MMIO page");
+        }
+    }
+
+    Ok(MmioRegion {
+        virt: virt_start + offset,
+        phys,
+        len,
+    })
+}
+
+/// Tear down a mapping made by [`map_mmio`] and release its virtual range.
+///
+/// Once the APs are up this waits for a TLB shootdown, so it must be called
+/// with interrupts enabled (see [`tlb::shootdown_allowed`]).
+///
+/// # Safety
+/// No pointer into the region may be used afterwards.
+pub unsafe fn unmap_mmio(region: MmioRegion) {
+    debug_assert!(tlb::shootdown_allowed(), "unmap_mmio with interrupts disabled");
+    let (start, size) = region.page_range();
+    for i in 0..size / PAGE_SIZE {
+        let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+        unsafe { paging::unmap_page(page) }.expect("MMIO page was not mapped");
+    }
+    // no core may keep a translation into the range once it is handed out again
+    tlb::shootdown(start, size / PAGE_SIZE);
+    MMIO_SPACE.lock().free(start, size);
+}
@@ -1,83 +1,109 @@
+pub mod demand;
+pub mod dma;
+pub mod frame;
 pub mod heap;
+pub mod mmio;
+pub mod paging;
+pub mod slab;
+pub mod stack;
+pub mod tlb;
+pub mod vmalloc;
+pub mod vspace;
 
-use limine::{memory_map::EntryType, response::MemoryMapResponse};
+use limine::{
+    memory_map::{Entry, EntryType},
+    response::MemoryMapResponse,
+};
+use paging::KernelAddress;
+use spin::{Mutex, Once};
+
+use crate::serial_println;
 use x86_64::{
-    structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
-    },
+    instructions::interrupts,
+    structures::paging::OffsetPageTable,
     PhysAddr, VirtAddr,
 };
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMapResponse,
-    next: usize,
-}
+pub use dma::{DmaBuffer, DmaConstraints};
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMapResponse) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-}
+static PHYS_OFFSET: Once<VirtAddr> = Once::new();
 
-impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let entries = self.memory_map.entries();
-        let regions = entries.iter().filter(|r| r.entry_type == EntryType::USABLE);
-        let addr_ranges = regions.map(|r| r.base..(r.base + r.length));
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
+/// The page table every core runs on, shared behind a lock.
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
+/// Translate a physical address into its HHDM virtual address.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    let offset = PHYS_OFFSET.get().expect("HHDM offset not initialized");
+    *offset + phys.as_u64()
 }
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    unsafe {
-        let level_4_table = active_level_4_table(physical_memory_offset);
-        OffsetPageTable::new(level_4_table, physical_memory_offset)
-    }
+/// Inverse of [`phys_to_virt`], for pointers Limine hands out inside the HHDM.
+pub fn hhdm_to_phys(virt: VirtAddr) -> PhysAddr {
+    let offset = PHYS_OFFSET.get().expect("HHDM offset not initialized");
+    PhysAddr::new(virt - *offset)
 }
 
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
+/// Bring up physical memory management and switch this core onto the
+/// kernel's own page table.
+///
+/// # Safety
+/// `physical_memory_offset`, `memory_map` and `kernel` must be what Limine
+/// reported, and the function must only be called once, on the BSP.
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+    memory_map: &MemoryMapResponse,
+    kernel: KernelAddress,
+) {
+    PHYS_OFFSET.call_once(|| physical_memory_offset);
+    unsafe { frame::init(memory_map) };
 
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+    let mapper =
+        unsafe { paging::build_kernel_page_table(memory_map, kernel, physical_memory_offset) }
+            .expect("failed to build the kernel page table");
+    unsafe { paging::load_kernel_page_table() };
+    MAPPER.call_once(|| Mutex::new(mapper));
+}
 
-    unsafe { &mut *page_table_ptr }
+/// Run `f` with the kernel page table locked. Interrupts are disabled for the
+/// duration so a fault or interrupt on this core can never deadlock on it.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mapper = MAPPER.get().expect("memory::init not called");
+    interrupts::without_interrupts(|| f(&mut mapper.lock()))
 }
 
-/// Map APIC base address to virtual memory
-pub unsafe fn map_apic(
-    mapper: &mut OffsetPageTable<'static>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), x86_64::structures::paging::mapper::MapToError<Size4KiB>> {
-    let apic_phys = PhysAddr::new(0xFEE0_0000);
-    let apic_page = Page::containing_address(VirtAddr::new(0xFEE0_0000));
+fn entry_type_name(entry_type: EntryType) -> &'static str {
+    match entry_type {
+        EntryType::USABLE => "usable",
+        EntryType::RESERVED => "reserved",
+        EntryType::ACPI_RECLAIMABLE => "ACPI reclaimable",
+        EntryType::ACPI_NVS => "ACPI NVS",
+        EntryType::BAD_MEMORY => "bad memory",
+        EntryType::BOOTLOADER_RECLAIMABLE => "bootloader reclaimable",
+        EntryType::EXECUTABLE_AND_MODULES => "kernel and modules",
+        EntryType::FRAMEBUFFER => "framebuffer",
+        _ => "unknown",
+    }
+}
 
-    // Map APIC page as writeable
-    unsafe {
-        let _ = mapper.map_to(
-            apic_page,
-            PhysFrame::containing_address(apic_phys),
-            x86_64::structures::paging::PageTableFlags::PRESENT
-                | x86_64::structures::paging::PageTableFlags::WRITABLE
-                | x86_64::structures::paging::PageTableFlags::NO_EXECUTE,
-            frame_allocator,
-        )?;
+/// Dump the boot memory map and the frame allocator totals over serial.
+pub fn print_memory_map(entries: &[Entry]) {
+    serial_println!("Memory map:");
+    for entry in entries {
+        serial_println!(
+            "  {:#014x}-{:#014x} {:>8} KiB  {}",
+            entry.base,
+            entry.base + entry.length,
+            entry.length / 1024,
+            entry_type_name(entry.entry_type)
+        );
     }
 
-    Ok(())
+    let stats = frame::stats();
+    let mib = |frames: usize| frames as u64 * frame::FRAME_SIZE / (1024 * 1024);
+    serial_println!(
+        "Physical memory: {} MiB usable, {} MiB used, {} MiB free",
+        mib(stats.usable_frames),
+        mib(stats.used_frames),
+        mib(stats.free_frames)
+    );
 }
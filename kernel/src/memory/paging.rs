@@ -0,0 +1,239 @@
+use limine::{memory_map::EntryType, response::MemoryMapResponse};
+use spin::Once;
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        mapper::{MapToError, MapperFlush, TranslateResult, UnmapError},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame::GlobalFrameAllocator, phys_to_virt, with_mapper};
+
+// Section boundaries exported by linker-x86_64.ld
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Where Limine loaded the kernel image.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelAddress {
+    pub physical_base: u64,
+    pub virtual_base: u64,
+}
+
+/// Memory map entry types that get a writable HHDM mapping. The framebuffer
+/// is left out on purpose: it is mapped write-combining through `mmio`, and
+/// aliasing it with a write-back mapping would be undefined.
+const HHDM_TYPES: [EntryType; 5] = [
+    EntryType::USABLE,
+    EntryType::BOOTLOADER_RECLAIMABLE,
+    EntryType::EXECUTABLE_AND_MODULES,
+    EntryType::ACPI_RECLAIMABLE,
+    EntryType::ACPI_NVS,
+];
+
+const HUGE_PAGE_SIZE: u64 = Size2MiB::SIZE;
+
+/// Physical frame holding the kernel PML4, loaded into CR3 on every core.
+static KERNEL_PML4: Once<PhysFrame> = Once::new();
+
+/// Build the kernel's own PML4: the kernel image mapped section by section
+/// with the permissions from the linker script, plus the HHDM.
+///
+/// # Safety
+/// The HHDM offset and the frame allocator must be initialized, and Limine's
+/// page tables must still be active so the new tables can be written through
+/// the HHDM.
+pub(super) unsafe fn build_kernel_page_table(
+    memory_map: &MemoryMapResponse,
+    kernel: KernelAddress,
+    physical_memory_offset: VirtAddr,
+) -> Result<OffsetPageTable<'static>, MapToError<Size4KiB>> {
+    let frame_allocator = &mut GlobalFrameAllocator;
+
+    let pml4_frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let pml4: &'static mut PageTable =
+        unsafe { &mut *phys_to_virt(pml4_frame.start_address()).as_mut_ptr() };
+    pml4.zero();
+    let mut mapper = unsafe { OffsetPageTable::new(pml4, physical_memory_offset) };
+
+    // the linker script places every allocated section inside one of these
+    // ranges; an orphan past __data_end would be left unmapped
+    let image_start = VirtAddr::from_ptr(&raw const __kernel_start);
+    let image_end = VirtAddr::from_ptr(&raw const __kernel_end);
+    assert!(
+        image_start == VirtAddr::from_ptr(&raw const __text_start)
+            && image_end <= VirtAddr::from_ptr(&raw const __data_end).align_up(Size4KiB::SIZE),
+        "kernel image has sections outside the mapped text, rodata and data ranges"
+    );
+
+    let sections = [
+        (&raw const __text_start, &raw const __text_end, PageTableFlags::empty()),
+        (&raw const __rodata_start, &raw const __rodata_end, PageTableFlags::NO_EXECUTE),
+        (
+            &raw const __data_start,
+            &raw const __data_end,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+    ];
+
+    for (start, end, flags) in sections {
+        let start = VirtAddr::from_ptr(start);
+        let end = VirtAddr::from_ptr(end);
+        if start == end {
+            continue;
+        }
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end - 1u64),
+        );
+        for page in pages {
+            let offset = page.start_address().as_u64() - kernel.virtual_base;
+            let frame = PhysFrame::containing_address(PhysAddr::new(kernel.physical_base + offset));
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)?
+                    .ignore();
+            }
+        }
+    }
+
+    let hhdm_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for region in memory_map.entries().iter().filter(|r| HHDM_TYPES.contains(&r.entry_type)) {
+        let mut phys = region.base & !(Size4KiB::SIZE - 1);
+        let end = region.base + region.length;
+
+        // 4 KiB pages up to the first 2 MiB boundary and after the last one,
+        // huge pages in between
+        let mut small_pages_until = 0;
+        while phys < end {
+            let virt = physical_memory_offset + phys;
+            let whole_block = phys % HUGE_PAGE_SIZE == 0 && phys + HUGE_PAGE_SIZE <= end;
+            if whole_block && phys >= small_pages_until {
+                let page = Page::<Size2MiB>::containing_address(virt);
+                let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(phys));
+                match unsafe { mapper.map_to(page, frame, hhdm_flags, frame_allocator) } {
+                    Ok(flush) => flush.ignore(),
+                    // an earlier region's unaligned edge left a page table for
+                    // this block; fill in the rest of it page by page
+                    Err(MapToError::PageAlreadyMapped(_)) => {
+                        small_pages_until = phys + HUGE_PAGE_SIZE;
+                        continue;
+                    }
+                    Err(MapToError::FrameAllocationFailed) => {
+                        return Err(MapToError::FrameAllocationFailed);
+                    }
+                    Err(MapToError::ParentEntryHugePage) => {
+                        return Err(MapToError::ParentEntryHugePage);
+                    }
+                }
+                phys += HUGE_PAGE_SIZE;
+            } else {
+                let page = Page::<Size4KiB>::containing_address(virt);
+                let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+                match unsafe { mapper.map_to(page, frame, hhdm_flags, frame_allocator) } {
+                    Ok(flush) => flush.ignore(),
+                    // two regions sharing a page, or a page inside a block an
+                    // earlier region mapped huge; the first mapping wins
+                    Err(MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage) => {}
+                    Err(e) => return Err(e),
+                }
+                phys += Size4KiB::SIZE;
+            }
+        }
+    }
+
+    KERNEL_PML4.call_once(|| pml4_frame);
+    Ok(mapper)
+}
+
+/// Switch the current core onto the kernel page table, enabling NX and
+/// supervisor write protection so the section permissions are enforced, and
+/// programming the PAT the MMIO cache types rely on.
+///
+/// # Safety
+/// [`build_kernel_page_table`] must have completed, and the caller must only
+/// be using memory that the kernel page table maps (its stack included).
+pub unsafe fn load_kernel_page_table() {
+    let pml4 = *KERNEL_PML4.get().expect("kernel page table not built");
+    super::mmio::init_pat();
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        let (_, cr3_flags) = Cr3::read();
+        Cr3::write(pml4, cr3_flags);
+    }
+}
+
+/// Translate a kernel virtual address through the active page table.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Return the flags of the leaf entry mapping `addr`, whatever its page size.
+pub fn flags_of(addr: VirtAddr) -> Option<PageTableFlags> {
+    with_mapper(|mapper| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    })
+}
+
+/// Map a single 4 KiB page. The TLB entry is flushed on the calling core.
+///
+/// # Safety
+/// Mapping `frame` must not create aliasing that breaks memory safety, e.g. a
+/// second writable mapping of memory owned elsewhere.
+pub unsafe fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| unsafe {
+        mapper
+            .map_to(page, frame, flags | PageTableFlags::PRESENT, &mut GlobalFrameAllocator)
+            .map(MapperFlush::flush)
+    })
+}
+
+/// Unmap a 4 KiB page and return the frame it pointed to; the frame itself is
+/// not freed. The TLB entry is flushed on the calling core only; other cores
+/// need a [`tlb::shootdown`](super::tlb::shootdown) before the frame is reused.
+///
+/// # Safety
+/// Nothing may still be using the mapping.
+pub unsafe fn unmap_page(page: Page) -> Result<PhysFrame, UnmapError> {
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Replace the flags of an existing 4 KiB mapping. Like [`unmap_page`], this
+/// only flushes the calling core's TLB.
+///
+/// # Safety
+/// Changing permissions or cache attributes must not break code that relies
+/// on the old ones.
+pub unsafe fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), &'static str> {
+    with_mapper(|mapper| unsafe {
+        mapper
+            .update_flags(page, flags | PageTableFlags::PRESENT)
+            .map(MapperFlush::flush)
+            .map_err(|_| "page is not mapped with a 4 KiB entry")
+    })
+}
@@ -0,0 +1,204 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use super::heap::GrowableHeap;
+use crate::percpu;
+
+/// Object sizes served from slabs. Anything larger (or more strictly aligned)
+/// goes straight to the page-level backend.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const NUM_CLASSES: usize = SIZE_CLASSES.len();
+
+/// Size of a slab fetched from the backend; objects are carved out of it.
+const SLAB_SIZE: usize = 4096;
+
+/// Objects a per-CPU magazine holds per size class.
+const MAGAZINE_CAPACITY: usize = 32;
+
+/// Free object, linked through its first word.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Shared free list for one size class, refilled from the backend a slab at
+/// a time. Only touched when a magazine runs empty or overflows.
+struct Depot {
+    head: *mut FreeObject,
+}
+
+unsafe impl Send for Depot {}
+
+impl Depot {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    fn pop(&mut self) -> *mut u8 {
+        let object = self.head;
+        if !object.is_null() {
+            self.head = unsafe { (*object).next };
+        }
+        object as *mut u8
+    }
+
+    fn push(&mut self, object: *mut u8) {
+        let object = object as *mut FreeObject;
+        unsafe { (*object).next = self.head };
+        self.head = object;
+    }
+
+    /// Split a fresh slab into objects of `size` bytes.
+    fn add_slab(&mut self, slab: *mut u8, size: usize) {
+        for i in (0..SLAB_SIZE / size).rev() {
+            self.push(unsafe { slab.add(i * size) });
+        }
+    }
+}
+
+/// Per-CPU stack of free objects for each size class.
+struct Magazine {
+    objects: [[*mut u8; MAGAZINE_CAPACITY]; NUM_CLASSES],
+    counts: [usize; NUM_CLASSES],
+}
+
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [[ptr::null_mut(); MAGAZINE_CAPACITY]; NUM_CLASSES],
+            counts: [0; NUM_CLASSES],
+        }
+    }
+}
+
+/// Size-class slab allocator with per-CPU magazines in front of a shared depot
+/// per class, which in turn is fed pages from a [`GrowableHeap`].
+///
+/// The fast path only touches the current core's magazine, so cores do not
+/// contend on a global lock for small allocations. Until the per-CPU
+/// magazines exist, and on a core that has no per-cpu block yet, everyone
+/// shares the boot magazine.
+pub struct SlabAllocator {
+    backend: &'static GrowableHeap,
+    depots: [Mutex<Depot>; NUM_CLASSES],
+    boot_magazine: Mutex<Magazine>,
+    /// Indexed by logical CPU number.
+    magazines: Once<&'static [Mutex<Magazine>]>,
+}
+
+impl SlabAllocator {
+    pub const fn new(backend: &'static GrowableHeap) -> Self {
+        Self {
+            backend,
+            depots: [const { Mutex::new(Depot::new()) }; NUM_CLASSES],
+            boot_magazine: Mutex::new(Magazine::new()),
+            magazines: Once::new(),
+        }
+    }
+
+    /// Give each of `num_cpus` CPUs its own magazine. They come straight from
+    /// the backend, outside what the allocator counts, and are never freed.
+    pub fn init_cpus(&self, num_cpus: usize) {
+        self.magazines.call_once(|| {
+            let layout = Layout::array::<Mutex<Magazine>>(num_cpus).unwrap();
+            let magazines = unsafe { self.backend.alloc(layout) } as *mut Mutex<Magazine>;
+            assert!(!magazines.is_null(), "no memory for the per-CPU magazines");
+            for i in 0..num_cpus {
+                unsafe { magazines.add(i).write(Mutex::new(Magazine::new())) };
+            }
+            unsafe { core::slice::from_raw_parts(magazines, num_cpus) }
+        });
+    }
+
+    pub fn backend(&self) -> &'static GrowableHeap {
+        self.backend
+    }
+
+    fn size_class(layout: Layout) -> Option<usize> {
+        // classes are powers of two and slabs are page aligned, so an object
+        // is always aligned to its own size
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| size <= class)
+    }
+
+    /// Magazine of the core we're running on.
+    fn magazine(&self) -> &Mutex<Magazine> {
+        let own = self.magazines.get().zip(percpu::try_this_cpu());
+        own.and_then(|(magazines, cpu)| magazines.get(cpu.index()))
+            .unwrap_or(&self.boot_magazine)
+    }
+
+    /// Move up to half a magazine's worth of objects from the depot (growing
+    /// it from the backend if needed) into `magazine`.
+    fn refill(&self, magazine: &mut Magazine, class: usize) -> bool {
+        let size = SIZE_CLASSES[class];
+        let mut depot = self.depots[class].lock();
+
+        while magazine.counts[class] < MAGAZINE_CAPACITY / 2 {
+            let mut object = depot.pop();
+            if object.is_null() {
+                let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+                let slab = unsafe { self.backend.alloc(layout) };
+                if slab.is_null() {
+                    break;
+                }
+                depot.add_slab(slab, size);
+                object = depot.pop();
+            }
+            magazine.objects[class][magazine.counts[class]] = object;
+            magazine.counts[class] += 1;
+        }
+
+        magazine.counts[class] > 0
+    }
+
+    /// Hand the older half of a full magazine back to the depot.
+    fn flush(&self, magazine: &mut Magazine, class: usize) {
+        let mut depot = self.depots[class].lock();
+        let keep = MAGAZINE_CAPACITY / 2;
+        for i in 0..keep {
+            depot.push(magazine.objects[class][i]);
+        }
+        magazine.objects[class].copy_within(keep..magazine.counts[class], 0);
+        magazine.counts[class] -= keep;
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = Self::size_class(layout) else {
+            return unsafe { self.backend.alloc(layout) };
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut magazine = self.magazine().lock();
+            if magazine.counts[class] == 0 && !self.refill(&mut magazine, class) {
+                return ptr::null_mut();
+            }
+            magazine.counts[class] -= 1;
+            magazine.objects[class][magazine.counts[class]]
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(class) = Self::size_class(layout) else {
+            return unsafe { self.backend.dealloc(ptr, layout) };
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut magazine = self.magazine().lock();
+            if magazine.counts[class] == MAGAZINE_CAPACITY {
+                self.flush(&mut magazine, class);
+            }
+            let count = magazine.counts[class];
+            magazine.objects[class][count] = ptr;
+            magazine.counts[class] += 1;
+        })
+    }
+}
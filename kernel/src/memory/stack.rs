@@ -0,0 +1,89 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{frame, paging, vspace::VirtualRegionAllocator};
+
+/// Virtual window kernel stacks are carved from.
+pub const STACK_WINDOW_START: u64 = 0xFFFF_E000_0000_0000;
+pub const STACK_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// Unmapped bytes left below every stack so an overflow faults instead of
+/// running into whatever sits underneath.
+pub const GUARD_SIZE: u64 = 4096;
+
+const PAGE_SIZE: u64 = 4096;
+
+lazy_static! {
+    static ref STACK_SPACE: Mutex<VirtualRegionAllocator> = Mutex::new(
+        VirtualRegionAllocator::new(VirtAddr::new(STACK_WINDOW_START), STACK_WINDOW_SIZE)
+    );
+}
+
+/// A kernel stack with an unmapped guard page directly below it.
+#[derive(Debug, Clone, Copy)]
+pub struct GuardedStack {
+    guard: VirtAddr,
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl GuardedStack {
+    /// Initial stack pointer (16-byte aligned).
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Lowest mapped address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Whether `addr` lies in this stack's guard page.
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        self.guard <= addr && addr < self.bottom
+    }
+}
+
+/// Allocate a stack of at least `size` bytes backed by fresh frames, with a
+/// guard page below it.
+pub fn allocate_stack(size: usize) -> Result<GuardedStack, &'static str> {
+    let size = (size as u64).next_multiple_of(PAGE_SIZE);
+    let guard = STACK_SPACE
+        .lock()
+        .allocate(GUARD_SIZE + size, PAGE_SIZE)
+        .ok_or("kernel stack window exhausted")?;
+    let bottom = guard + GUARD_SIZE;
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let page = |i: u64| Page::<Size4KiB>::containing_address(bottom + i * PAGE_SIZE);
+    for i in 0..size / PAGE_SIZE {
+        let mapped = frame::allocate_frame()
+            .ok_or("out of frames for kernel stack")
+            .and_then(|frame| {
+                unsafe { paging::map_page(page(i), frame, flags) }.map_err(|_| {
+                    unsafe { frame::deallocate_frame(frame) };
+                    "failed to map kernel stack"
+                })
+            });
+        if let Err(e) = mapped {
+            // roll back what was mapped so far; no one has used the stack,
+            // so no other core can have it in its TLB
+            for j in 0..i {
+                let frame = unsafe { paging::unmap_page(page(j)) }.expect("stack page was not mapped");
+                unsafe { frame::deallocate_frame(frame) };
+            }
+            STACK_SPACE.lock().free(guard, GUARD_SIZE + size);
+            return Err(e);
+        }
+    }
+
+    Ok(GuardedStack {
+        guard,
+        bottom,
+        top: bottom + size,
+    })
+}
@@ -0,0 +1,45 @@
+use x86_64::{
+    instructions::{interrupts, tlb},
+    VirtAddr,
+};
+
+use crate::ipi;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Past this many pages one full flush is cheaper than flushing page by page.
+const FULL_FLUSH_PAGES: u64 = 32;
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FULL_FLUSH_PAGES {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(start + i * PAGE_SIZE);
+        }
+    }
+}
+
+/// Drop `pages` pages starting at `start` from the TLB of every CPU and
+/// return once all of them have acknowledged.
+///
+/// All cores share the kernel page table, so any of them may have cached the
+/// old translation. Call this after unmapping or changing the entries and
+/// before the frames or the virtual range are handed out again. Needs
+/// interrupts enabled once the APs are up (see [`ipi::call_on_others`]).
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    flush_local(start, pages);
+    ipi::call_on_others(&|| flush_local(start, pages));
+}
+
+/// Whether [`shootdown`] may run here. Once an AP takes IPIs it waits for
+/// the other cores, which needs interrupts enabled: not from interrupt
+/// handlers, timer callbacks or `without_interrupts`.
+pub fn shootdown_allowed() -> bool {
+    interrupts::are_enabled() || !ipi::others_ready()
+}
+
+/// [`shootdown`] for a single page.
+pub fn shootdown_page(addr: VirtAddr) {
+    shootdown(addr.align_down(PAGE_SIZE), 1);
+}
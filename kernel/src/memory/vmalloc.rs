@@ -0,0 +1,166 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{demand, frame, paging, tlb, vspace::VirtualRegionAllocator};
+
+/// Virtual window large, virtually contiguous buffers are carved from.
+pub const VMALLOC_WINDOW_START: u64 = 0xFFFF_C000_0000_0000;
+pub const VMALLOC_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// Unmapped bytes left after every region so an overrun faults instead of
+/// running into the next one.
+const GUARD_SIZE: u64 = 4096;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Pages unmapped per TLB shootdown when a region is released.
+const RELEASE_BATCH: usize = 32;
+
+lazy_static! {
+    static ref VMALLOC_SPACE: Mutex<VirtualRegionAllocator> = Mutex::new(
+        VirtualRegionAllocator::new(VirtAddr::new(VMALLOC_WINDOW_START), VMALLOC_WINDOW_SIZE)
+    );
+}
+
+/// A zeroed, virtually contiguous buffer backed by individually allocated
+/// frames.
+///
+/// The memory stays allocated until passed to [`vfree`]; dropping the handle
+/// leaks it, which is what buffers living for the rest of the uptime want.
+#[derive(Debug)]
+pub struct VmRegion {
+    start: VirtAddr,
+    size: u64,
+    /// Backed on first touch rather than up front.
+    lazy: bool,
+}
+
+impl VmRegion {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Usable size in bytes (whole pages).
+    pub fn len(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+
+    fn page(&self, index: u64) -> Page<Size4KiB> {
+        Page::containing_address(self.start + index * PAGE_SIZE)
+    }
+
+    /// Unmap the first `pages` pages and give their frames back once no CPU
+    /// can still reach them through its TLB. Pages of a lazy region that
+    /// were never touched are skipped. Works in batches on the stack so
+    /// releasing never allocates, not even after running out of memory.
+    unsafe fn release_pages(&self, pages: u64) {
+        let mut frames = [PhysFrame::containing_address(PhysAddr::zero()); RELEASE_BATCH];
+        for first in (0..pages).step_by(RELEASE_BATCH) {
+            let count = (pages - first).min(RELEASE_BATCH as u64);
+            let mut unmapped = 0;
+            for i in first..first + count {
+                match unsafe { paging::unmap_page(self.page(i)) } {
+                    Ok(frame) => {
+                        frames[unmapped] = frame;
+                        unmapped += 1;
+                    }
+                    Err(_) if self.lazy => continue,
+                    Err(e) => panic!("vmalloc page was not mapped: {:?}", e),
+                }
+            }
+            if unmapped == 0 {
+                continue;
+            }
+            tlb::shootdown(self.page(first).start_address(), count);
+            for &frame in &frames[..unmapped] {
+                unsafe { frame::deallocate_frame(frame) };
+            }
+        }
+    }
+}
+
+/// Reserve at least `size` bytes of kernel virtual address space and back
+/// them with fresh, zeroed frames. The frames need not be physically
+/// contiguous.
+pub fn vmalloc(size: usize) -> Result<VmRegion, &'static str> {
+    if size == 0 {
+        return Err("empty vmalloc request");
+    }
+    let size = (size as u64).next_multiple_of(PAGE_SIZE);
+    let start = VMALLOC_SPACE
+        .lock()
+        .allocate(size + GUARD_SIZE, PAGE_SIZE)
+        .ok_or("vmalloc window exhausted")?;
+    let region = VmRegion { start, size, lazy: false };
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for i in 0..size / PAGE_SIZE {
+        let mapped = frame::allocate_frame()
+            .ok_or("out of frames for vmalloc")
+            .and_then(|frame| {
+                unsafe { paging::map_page(region.page(i), frame, flags) }.map_err(|_| {
+                    unsafe { frame::deallocate_frame(frame) };
+                    "failed to map vmalloc page"
+                })
+            });
+        if let Err(e) = mapped {
+            // roll back what was mapped so far
+            unsafe { region.release_pages(i) };
+            VMALLOC_SPACE.lock().free(start, size + GUARD_SIZE);
+            return Err(e);
+        }
+    }
+
+    unsafe { region.as_mut_ptr::<u8>().write_bytes(0, size as usize) };
+    Ok(region)
+}
+
+/// Reserve at least `size` bytes of kernel virtual address space without
+/// backing it; each page gets a zeroed frame the first time it is touched.
+/// Suited to large reserves (heaps, stacks) that are mostly never used.
+pub fn vmalloc_lazy(size: usize) -> Result<VmRegion, &'static str> {
+    if size == 0 {
+        return Err("empty vmalloc request");
+    }
+    let size = (size as u64).next_multiple_of(PAGE_SIZE);
+    let start = VMALLOC_SPACE
+        .lock()
+        .allocate(size + GUARD_SIZE, PAGE_SIZE)
+        .ok_or("vmalloc window exhausted")?;
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(e) = demand::register_lazy_region(start, size, flags) {
+        VMALLOC_SPACE.lock().free(start, size + GUARD_SIZE);
+        return Err(e);
+    }
+    Ok(VmRegion { start, size, lazy: true })
+}
+
+/// Unmap a region made by [`vmalloc`] or [`vmalloc_lazy`], free its frames
+/// and release the virtual range.
+///
+/// Once the APs are up this waits for TLB shootdowns, so it must be called
+/// with interrupts enabled (see [`tlb::shootdown_allowed`]).
+///
+/// # Safety
+/// No pointer into the region may be used afterwards.
+pub unsafe fn vfree(region: VmRegion) {
+    debug_assert!(tlb::shootdown_allowed(), "vfree with interrupts disabled");
+    if region.lazy {
+        demand::unregister_lazy_region(region.start).expect("lazy vmalloc region not registered");
+    }
+    unsafe { region.release_pages(region.size / PAGE_SIZE) };
+    VMALLOC_SPACE.lock().free(region.start, region.size + GUARD_SIZE);
+}
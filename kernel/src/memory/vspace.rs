@@ -0,0 +1,75 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use x86_64::VirtAddr;
+
+/// First-fit allocator for a window of kernel virtual address space.
+///
+/// Only hands out address ranges; backing them with frames is up to the
+/// caller. Free ranges are kept sorted and are merged with their neighbours
+/// when released.
+pub struct VirtualRegionAllocator {
+    window: Range<u64>,
+    free: Vec<Range<u64>>,
+}
+
+impl VirtualRegionAllocator {
+    pub fn new(start: VirtAddr, size: u64) -> Self {
+        let window = start.as_u64()..start.as_u64() + size;
+        Self {
+            free: alloc::vec![window.clone()],
+            window,
+        }
+    }
+
+    /// Reserve `size` bytes aligned to `align` (a power of two).
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        let (index, start) = self.free.iter().enumerate().find_map(|(i, range)| {
+            let start = range.start.next_multiple_of(align);
+            (start + size <= range.end).then_some((i, start))
+        })?;
+
+        let range = self.free.remove(index);
+        if start + size < range.end {
+            self.free.insert(index, start + size..range.end);
+        }
+        if range.start < start {
+            self.free.insert(index, range.start..start);
+        }
+        Some(VirtAddr::new(start))
+    }
+
+    /// Give back a range previously returned by [`allocate`](Self::allocate).
+    pub fn free(&mut self, start: VirtAddr, size: u64) {
+        let start = start.as_u64();
+        let end = start + size;
+        assert!(
+            self.window.start <= start && end <= self.window.end,
+            "freeing {:#x}..{:#x} outside of the region window",
+            start,
+            end
+        );
+
+        let index = self.free.partition_point(|range| range.end <= start);
+        if let Some(next) = self.free.get(index) {
+            assert!(end <= next.start, "double free of virtual range {:#x}", start);
+        }
+
+        let merge_prev = index > 0 && self.free[index - 1].end == start;
+        let merge_next = self.free.get(index).is_some_and(|next| next.start == end);
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.free[index - 1].end = self.free[index].end;
+                self.free.remove(index);
+            }
+            (true, false) => self.free[index - 1].end = end,
+            (false, true) => self.free[index].start = start,
+            (false, false) => self.free.insert(index, start..end),
+        }
+    }
+
+    /// Whether `addr` falls inside the window this allocator manages.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.window.contains(&addr.as_u64())
+    }
+}
@@ -0,0 +1,130 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::boot::boot_info;
+use crate::gdt;
+
+/// Counters a core keeps about itself. Other cores may read them at any
+/// time, so they are only ever approximately in step with each other.
+#[derive(Debug, Default)]
+pub struct CpuStats {
+    /// Device and inter-processor interrupts taken.
+    pub interrupts: AtomicU64,
+    /// Timer callbacks run from this core's timer queue.
+    pub timers_fired: AtomicU64,
+    /// Cross calls run on behalf of another core.
+    pub cross_calls: AtomicU64,
+}
+
+/// One core's private data, found through its IA32_GS_BASE.
+///
+/// `self_ptr` comes first so [`this_cpu`] is a single `gs`-relative load.
+#[repr(C, align(64))]
+pub struct PerCpu {
+    self_ptr: *const PerCpu,
+    index: usize,
+    apic_id: u32,
+    tss: *const TaskStateSegment,
+    /// Id of whatever this core is running, 0 while idle.
+    current_task: AtomicU64,
+    pub stats: CpuStats,
+}
+
+// `self_ptr` and `tss` point at data that lives as long as the kernel and
+// is only changed before the core starts using it.
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+impl PerCpu {
+    /// Position in `BootInfo::cpus`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn tss(&self) -> &'static TaskStateSegment {
+        unsafe { &*self.tss }
+    }
+
+    pub fn current_task(&self) -> Option<u64> {
+        match self.current_task.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Record what this core is running now; `None` when it goes idle.
+    pub fn set_current_task(&self, task: Option<u64>) {
+        self.current_task.store(task.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+/// Indexed like `BootInfo::cpus`; never resized, so the blocks stay put.
+static CPUS: Once<Vec<PerCpu>> = Once::new();
+
+/// Build a block for every CPU in the boot info. Runs once on the BSP, after
+/// the per-cpu TSSs exist and before any AP is started.
+pub fn init() {
+    CPUS.call_once(|| {
+        let mut cpus: Vec<PerCpu> = boot_info()
+            .cpus
+            .iter()
+            .enumerate()
+            .map(|(index, cpu)| PerCpu {
+                self_ptr: core::ptr::null(),
+                index,
+                apic_id: cpu.lapic_id,
+                tss: gdt::tss_for(index),
+                current_task: AtomicU64::new(0),
+                stats: CpuStats::default(),
+            })
+            .collect();
+        for cpu in &mut cpus {
+            cpu.self_ptr = cpu as *const PerCpu;
+        }
+        cpus
+    });
+    crate::memory::heap::init_cpus(cpus().len());
+}
+
+/// Every CPU's block, for looking at other cores.
+pub fn cpus() -> &'static [PerCpu] {
+    CPUS.get().expect("per-cpu data not initialized")
+}
+
+/// Point this core's GS base at the block of CPU `index`.
+pub fn load(index: usize) {
+    let cpu = &cpus()[index];
+    GsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+/// The calling core's block, or `None` before [`load`] ran on this core.
+/// Costs an MSR read over [`this_cpu`], for code that also runs early.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    let cpus = CPUS.get()?;
+    let base = GsBase::read().as_ptr::<PerCpu>();
+    cpus.as_ptr_range().contains(&base).then(|| unsafe { &*base })
+}
+
+/// The calling core's block.
+///
+/// Usable anywhere once [`load`] ran on this core, interrupt handlers
+/// included: nothing ever changes a core's GS base afterwards, so the result
+/// stays right even if the caller is interrupted.
+#[inline]
+pub fn this_cpu() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*ptr
+    }
+}
@@ -18,6 +18,16 @@ pub fn _print(args: ::core::fmt::Arguments) {
     // SERIAL.lock().write_fmt(args).unwrap();
 }
 
+#[doc(hidden)]
+pub fn _log(args: ::core::fmt::Arguments) {
+    let uptime = crate::time::uptime();
+    let (secs, micros) = (uptime.as_secs(), uptime.subsec_micros());
+    match crate::time::wall_clock_now() {
+        Some(now) => _print(format_args!("[{:5}.{:06} {:#}] {}\n", secs, micros, now, args)),
+        None => _print(format_args!("[{:5}.{:06}] {}\n", secs, micros, args)),
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -35,6 +45,15 @@ macro_rules! serial_println {
         concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Prints a line to the host through the serial interface, prefixed with the
+/// time since boot.
+#[macro_export]
+macro_rules! serial_log {
+    ($($arg:tt)*) => {
+        $crate::serial::_log(format_args!($($arg)*))
+    };
+}
+
 lazy_static! {
     pub static ref SERIAL: Mutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
@@ -0,0 +1,24 @@
+use crate::acpi;
+use crate::boot::boot_info;
+
+pub fn test_acpi_tables() {
+    // 1) checksums: all bytes must sum to zero
+    assert!(acpi::checksum_ok(&[0x10, 0xF0, 0x00]));
+    assert!(!acpi::checksum_ok(&[0x10, 0xF0, 0x01]));
+
+    // 2) every CPU Limine started is an enabled local APIC in the MADT
+    let madt = acpi::madt().expect("no MADT");
+    for cpu in &boot_info().cpus {
+        assert!(madt.enabled_cpus().any(|lapic| lapic.apic_id == cpu.lapic_id));
+    }
+
+    // 3) q35 has a FADT, an HPET and PCI Express configuration space
+    let fadt = acpi::fadt().expect("no FADT");
+    assert!(fadt.pm_timer.is_some());
+    let hpet = acpi::hpet().expect("no HPET");
+    assert!(hpet.comparators >= 3);
+    let segment = acpi::pci_segment(0, 0).expect("no MCFG entry for bus 0");
+    assert_eq!(segment.config_address(0, 0, 0), Some(segment.base));
+    assert_eq!(segment.config_address(0, 1, 2), Some(segment.base + 0xA000u64));
+    assert_eq!(segment.config_address(0, 32, 0), None);
+}
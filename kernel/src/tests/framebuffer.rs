@@ -82,7 +82,8 @@ pub fn test_screen() {
 
     // NOTE: We intentionally leaked the box (Box::leak). If you want to free it later,
     // store the raw pointer / Layout so you can reconstruct and free it. For tests/demos
-    // leaking once is normally acceptable.
+    // leaking once is normally acceptable, so test_main registers this test
+    // with `Leaks::Allowed`.
 }
 
 /// Fill whole buffer with one color.
@@ -23,3 +23,72 @@ pub fn test_heap_allocations() {
     assert_eq!(Rc::strong_count(&rc_a), 2);
     assert_eq!(&*rc_a, &*rc_b);
 }
+
+pub fn test_heap_growth() {
+    use crate::memory::heap::{self, HEAP_INITIAL_SIZE};
+    use alloc::vec::Vec;
+
+    // allocate more than the initial heap so it has to map extra pages
+    let big: Vec<u8> = alloc::vec![0xAB; 2 * HEAP_INITIAL_SIZE];
+    assert!(big.iter().all(|&b| b == 0xAB));
+
+    let stats = heap::stats();
+    assert!(stats.size > HEAP_INITIAL_SIZE);
+    assert!(stats.size <= stats.max_size);
+}
+
+pub fn test_slab_size_classes() {
+    use alloc::{boxed::Box, vec::Vec};
+    use core::alloc::Layout;
+
+    // 1) every small size class hands out aligned, distinct, writable objects
+    let mut objects: Vec<(*mut u8, Layout)> = Vec::new();
+    for shift in 0..12 {
+        let layout = Layout::from_size_align(1 << shift, (1 << shift).min(64)).unwrap();
+        for _ in 0..64 {
+            let ptr = unsafe { alloc::alloc::alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % layout.align(), 0);
+            unsafe { ptr.write_bytes(shift as u8, layout.size()) };
+            objects.push((ptr, layout));
+        }
+    }
+    for (ptr, layout) in &objects {
+        assert_eq!(unsafe { **ptr }, layout.size().trailing_zeros() as u8);
+    }
+    for (ptr, layout) in objects.drain(..) {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+
+    // 2) freed objects are reused
+    let a = Box::new([0u8; 48]);
+    let addr = &*a as *const _ as usize;
+    drop(a);
+    let b = Box::new([1u8; 48]);
+    assert_eq!(&*b as *const _ as usize, addr);
+}
+
+pub fn test_heap_stats() {
+    use crate::memory::heap::{self, HeapStats};
+    use alloc::{boxed::Box, vec::Vec};
+
+    let before = heap::stats();
+
+    // 1) live allocations show up in the counters and the histogram
+    let small = Box::new([0u8; 24]); // 32 byte bucket
+    let large: Vec<u8> = Vec::with_capacity(100_000);
+    let during = heap::stats();
+    assert_eq!(during.live_allocations, before.live_allocations + 2);
+    assert_eq!(during.allocated, before.allocated + 24 + 100_000);
+    assert!(during.peak_allocated >= during.allocated);
+    assert_eq!(during.histogram[1], before.histogram[1] + 1);
+    assert_eq!(HeapStats::bucket_limit(1), Some(32));
+
+    // 2) freeing brings them back, the peak stays
+    drop(small);
+    drop(large);
+    let after = heap::stats();
+    assert_eq!(after.live_allocations, before.live_allocations);
+    assert_eq!(after.allocated, before.allocated);
+    assert!(after.peak_allocated >= during.allocated);
+}
@@ -0,0 +1,294 @@
+use alloc::format;
+
+use crate::exception::ErrorCode;
+
+pub fn test_exception_error_codes() {
+    // 1) selector error codes name the table and index
+    let gp = ErrorCode { vector: 13, code: (5 << 3) | 0b010 | 1 };
+    assert_eq!(format!("{}", gp), "0x2b (IDT index 0x5, external)");
+    let np = ErrorCode { vector: 11, code: 0x10 };
+    assert_eq!(format!("{}", np), "0x10 (GDT index 0x2)");
+
+    // 2) control protection errors are spelled out
+    let cp = ErrorCode { vector: 21, code: 3 };
+    assert_eq!(format!("{}", cp), "0x3 (missing ENDBRANCH)");
+
+    // 3) everything else is just the raw value
+    let ac = ErrorCode { vector: 17, code: 0 };
+    assert_eq!(format!("{}", ac), "0x0");
+}
+
+pub fn test_irq_registration() {
+    use crate::apic::{self, send_ipi, IpiDestination, IpiKind};
+    use crate::interrupt::{
+        allocate_vector, irq_count, register_irq, unregister_irq, IrqController, DYNAMIC_VECTORS,
+    };
+    use crate::time::{Duration, Instant};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+    fn handler(_vector: u8) {
+        HITS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    const VECTOR: u8 = 0xEF;
+    let before = irq_count(VECTOR);
+
+    // 1) a registered handler runs and is counted
+    register_irq(VECTOR, handler, IrqController::Apic).expect("vector taken");
+    assert!(register_irq(VECTOR, handler, IrqController::Apic).is_err());
+    assert!(register_irq(14, handler, IrqController::Apic).is_err());
+    unsafe { core::arch::asm!("int 0xEF") };
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(irq_count(VECTOR), before + 1);
+
+    // 2) once removed, the vector is reported instead of being fatal
+    unregister_irq(VECTOR).unwrap();
+    unsafe { core::arch::asm!("int 0xEF") };
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(irq_count(VECTOR), before + 2);
+
+    // 3) dynamic vectors come from the reserved range
+    let vector = allocate_vector(handler, IrqController::Apic).expect("no free vector");
+    assert!(DYNAMIC_VECTORS.contains(&vector));
+
+    // 4) a failed registration leaves the owner's EOI alone: a missed APIC
+    //    EOI would keep the vector in service and block the second IPI
+    fn pic_handler(_vector: u8) {}
+    assert!(register_irq(vector, pic_handler, IrqController::Pic).is_err());
+    let own = IpiDestination::Apic(apic::get_apic_id());
+    for _ in 0..2 {
+        let count = irq_count(vector);
+        let deadline = Instant::now() + Duration::from_millis(100);
+        send_ipi(own, IpiKind::Fixed(vector)).unwrap();
+        while irq_count(vector) == count {
+            assert!(Instant::now() < deadline, "vector {:#x} never fired again", vector);
+            core::hint::spin_loop();
+        }
+    }
+    unregister_irq(vector).unwrap();
+}
+
+pub fn test_ioapic_routing() {
+    use crate::acpi::{self, Polarity, TriggerMode};
+    use crate::ioapic;
+
+    // 1) QEMU describes its I/O APIC and the IRQ0 -> GSI 2 override in the MADT
+    let madt = acpi::madt().expect("no MADT");
+    assert!(!madt.io_apics.is_empty());
+    for o in &madt.overrides {
+        assert_eq!(ioapic::isa_irq_to_gsi(o.isa_irq), (o.gsi, o.polarity, o.trigger));
+    }
+    if !madt.overrides.iter().any(|o| o.isa_irq == ioapic::ISA_KEYBOARD) {
+        let keyboard = ioapic::isa_irq_to_gsi(ioapic::ISA_KEYBOARD);
+        assert_eq!(keyboard, (1, Polarity::ActiveHigh, TriggerMode::Edge));
+    }
+
+    // 2) bad destinations and GSIs are rejected
+    assert!(ioapic::route_gsi(1, 0x40, 0x100, Polarity::ActiveHigh, TriggerMode::Edge).is_err());
+    assert!(ioapic::set_gsi_masked(100_000, true).is_err());
+
+    // 3) the keyboard line can be masked and unmasked in place
+    let (gsi, _, _) = ioapic::isa_irq_to_gsi(ioapic::ISA_KEYBOARD);
+    ioapic::set_gsi_masked(gsi, true).unwrap();
+    ioapic::set_gsi_masked(gsi, false).unwrap();
+}
+
+pub fn test_apic_id() {
+    use crate::apic;
+    use crate::boot::boot_info;
+
+    // 1) the mode follows the CPU's capabilities
+    assert_eq!(apic::is_x2apic(), apic::x2apic_supported());
+
+    // 2) the ID is the one Limine reported for this CPU, not the raw register
+    let id = apic::get_apic_id();
+    assert!(boot_info().cpus.iter().any(|cpu| cpu.lapic_id == id));
+
+    // 3) and matches what CPUID says about the running core
+    let cpuid_id = if apic::is_x2apic() {
+        unsafe { core::arch::x86_64::__cpuid_count(0xB, 0) }.edx
+    } else {
+        unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24
+    };
+    assert_eq!(id, cpuid_id);
+}
+
+pub fn test_ipi_cross_call() {
+    use crate::boot::boot_info;
+    use crate::interrupt::{irq_count, unregister_irq};
+    use crate::{apic, ipi};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // 1) a registered IPI handler runs when the vector is sent to ourselves
+    fn handler(_vector: u8) {}
+    let vector = ipi::register_ipi_handler(handler).unwrap();
+    assert!(ipi::IPI_VECTORS.contains(&vector));
+    let before = irq_count(vector);
+    ipi::send_ipi(ipi::IpiDestination::Apic(apic::get_apic_id()), ipi::IpiKind::Fixed(vector)).unwrap();
+    while irq_count(vector) == before {
+        core::hint::spin_loop();
+    }
+    unregister_irq(vector).unwrap();
+
+    // 2) every other ready CPU runs the function exactly once before the call returns
+    let cpus = &boot_info().cpus;
+    let others = (0..cpus.len())
+        .filter(|&i| ipi::is_ready(i) && cpus[i].lapic_id != apic::get_apic_id())
+        .count();
+    let calls = AtomicUsize::new(0);
+    ipi::call_on_others(&|| {
+        calls.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(calls.load(Ordering::SeqCst), others);
+
+    // 3) targeting one CPU, ourselves included
+    for i in (0..cpus.len()).filter(|&i| ipi::is_ready(i)) {
+        let ran_on = AtomicUsize::new(usize::MAX);
+        ipi::call_on_cpu(i, &|| ran_on.store(apic::get_apic_id() as usize, Ordering::SeqCst)).unwrap();
+        assert_eq!(ran_on.load(Ordering::SeqCst), cpus[i].lapic_id as usize);
+    }
+    assert!(ipi::call_on_cpu(cpus.len(), &|| {}).is_err());
+}
+
+pub fn test_nmi() {
+    use crate::boot::{boot_info, BSP_INDEX};
+    use crate::exception::{register_nmi_handler, unregister_nmi_handler};
+    use crate::ipi::{self, IpiDestination, IpiKind};
+    use crate::percpu::this_cpu;
+    use crate::time::{Duration, Instant};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static RAN_ON: AtomicUsize = AtomicUsize::new(usize::MAX);
+    fn handler() {
+        RAN_ON.store(this_cpu().index(), Ordering::SeqCst);
+    }
+
+    // 1) only one handler at a time
+    register_nmi_handler(handler).unwrap();
+    assert!(register_nmi_handler(handler).is_err());
+
+    // 2) an NMI sent to an AP runs the handler there, and the AP carries on
+    let cpus = &boot_info().cpus;
+    if let Some(ap) = (0..cpus.len()).find(|&i| i != BSP_INDEX && ipi::is_ready(i)) {
+        RAN_ON.store(usize::MAX, Ordering::SeqCst);
+        ipi::send_ipi(IpiDestination::Apic(cpus[ap].lapic_id), IpiKind::Nmi).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while RAN_ON.load(Ordering::SeqCst) != ap {
+            assert!(Instant::now() < deadline, "CPU {} did not take the NMI", ap);
+            core::hint::spin_loop();
+        }
+        let answered = AtomicUsize::new(0);
+        ipi::call_on_cpu(ap, &|| {
+            answered.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+    }
+    unregister_nmi_handler();
+}
+
+pub fn test_percpu() {
+    use crate::percpu::{cpus, this_cpu};
+    use crate::{apic, gdt, ipi, time};
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // 1) the BSP's block describes the BSP
+    let cpu = this_cpu();
+    assert!(core::ptr::eq(cpu, &cpus()[cpu.index()]));
+    assert_eq!(cpu.apic_id(), apic::get_apic_id());
+    let stack_top = gdt::kernel_stack_top(cpu.index());
+    let rsp0 = { cpu.tss().privilege_stack_table }[0];
+    assert_eq!(rsp0, stack_top);
+
+    // 2) every other core finds its own block
+    let seen: Vec<AtomicBool> = cpus().iter().map(|_| AtomicBool::new(false)).collect();
+    let calls_before: Vec<u64> =
+        cpus().iter().map(|c| c.stats.cross_calls.load(Ordering::Relaxed)).collect();
+    ipi::call_on_others(&|| {
+        let me = this_cpu();
+        assert_eq!(me.apic_id(), apic::get_apic_id());
+        seen[me.index()].store(true, Ordering::SeqCst);
+    });
+    for (i, other) in cpus().iter().enumerate() {
+        if seen[i].load(Ordering::SeqCst) {
+            assert!(ipi::is_ready(i));
+            assert!(other.stats.cross_calls.load(Ordering::Relaxed) > calls_before[i]);
+        }
+    }
+    assert!(!seen[cpu.index()].load(Ordering::SeqCst));
+
+    // 3) interrupts taken here are counted here
+    let interrupts = cpu.stats.interrupts.load(Ordering::Relaxed);
+    let timers = cpu.stats.timers_fired.load(Ordering::Relaxed);
+    time::sleep(time::Duration::from_millis(5));
+    assert!(cpu.stats.interrupts.load(Ordering::Relaxed) > interrupts);
+    assert!(cpu.stats.timers_fired.load(Ordering::Relaxed) > timers);
+
+    // 4) current task bookkeeping
+    let previous = cpu.current_task();
+    cpu.set_current_task(Some(42));
+    assert_eq!(this_cpu().current_task(), Some(42));
+    cpu.set_current_task(previous);
+}
+
+pub fn test_cpu_numbering() {
+    use crate::apic;
+    use crate::boot::{boot_info, BSP_INDEX};
+    use crate::framebuffer::screen::framework::choose_worker_count_excluding_bsp;
+    use crate::percpu::this_cpu;
+
+    // 1) the tests run on the BSP, which is logical CPU 0
+    let info = boot_info();
+    assert_eq!(info.bsp().lapic_id, apic::get_apic_id());
+    assert_eq!(this_cpu().index(), BSP_INDEX);
+
+    // 2) the APs follow in ascending APIC ID order and map back to their number
+    let aps = &info.cpus[BSP_INDEX + 1..];
+    assert!(aps.windows(2).all(|pair| pair[0].lapic_id < pair[1].lapic_id));
+    assert_eq!(aps.len(), info.ap_count());
+    for (i, cpu) in info.cpus.iter().enumerate() {
+        assert_eq!(info.cpu_index(cpu.lapic_id), Some(i));
+    }
+
+    // 3) worker numbers are AP numbers, and the BSP has none
+    assert_eq!(info.ap_index(BSP_INDEX), None);
+    assert_eq!(info.ap_index(info.cpus.len()), None);
+    for i in 1..info.cpus.len() {
+        assert_eq!(info.ap_index(i), Some(i - 1));
+    }
+    let workers = choose_worker_count_excluding_bsp();
+    assert!(workers <= info.ap_count() && workers.is_multiple_of(2));
+}
+
+pub fn test_cpu_count_sizing() {
+    use crate::boot::boot_info;
+    use crate::percpu::{cpus, this_cpu, try_this_cpu};
+    use crate::{gdt, ipi};
+    use alloc::boxed::Box;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // 1) TSSs, per-cpu blocks and stacks exist for exactly the CPUs present
+    let count = boot_info().cpus.len();
+    assert_eq!(gdt::initialized_cpus(), count);
+    assert_eq!(cpus().len(), count);
+    for (i, cpu) in cpus().iter().enumerate() {
+        let rsp0 = { cpu.tss().privilege_stack_table }[0];
+        assert_eq!(rsp0, gdt::kernel_stack_top(i));
+        assert!(cpus()[..i].iter().all(|other| !core::ptr::eq(other.tss(), cpu.tss())));
+    }
+
+    // 2) the slow lookup agrees with the fast one
+    assert!(core::ptr::eq(try_this_cpu().unwrap(), this_cpu()));
+
+    // 3) every core allocates from its own magazine without trouble
+    let allocated = AtomicUsize::new(0);
+    ipi::call_on_all(&|| {
+        assert!(try_this_cpu().is_some());
+        let boxes: [Box<u64>; 8] = core::array::from_fn(|i| Box::new(i as u64));
+        assert!(boxes.iter().enumerate().all(|(i, b)| **b == i as u64));
+        allocated.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(allocated.load(Ordering::SeqCst) >= 1);
+}
@@ -0,0 +1,253 @@
+use crate::memory::frame;
+
+pub fn test_frame_allocator() {
+    let before = frame::stats();
+
+    // 1) single frames are distinct and page aligned
+    let a = frame::allocate_frame().expect("out of frames");
+    let b = frame::allocate_frame().expect("out of frames");
+    assert_ne!(a, b);
+    assert_eq!(a.start_address().as_u64() % frame::FRAME_SIZE, 0);
+    assert_eq!(frame::stats().free_frames, before.free_frames - 2);
+
+    // 2) contiguous runs honour the requested alignment
+    let run = frame::allocate_contiguous(16, 16).expect("no contiguous run");
+    assert_eq!(run.start_address().as_u64() % (16 * frame::FRAME_SIZE), 0);
+
+    // 3) everything comes back
+    unsafe {
+        frame::deallocate_contiguous(run, 16);
+        frame::deallocate_frame(b);
+        frame::deallocate_frame(a);
+    }
+    assert_eq!(frame::stats().free_frames, before.free_frames);
+}
+
+pub fn test_kernel_page_permissions() {
+    use crate::memory::paging;
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+    static RODATA: [u8; 4] = [1, 2, 3, 4];
+    static mut DATA: u64 = 0;
+
+    // text: executable, read-only
+    let text = paging::flags_of(VirtAddr::new(test_kernel_page_permissions as usize as u64))
+        .expect("text not mapped");
+    assert!(!text.contains(PageTableFlags::WRITABLE));
+    assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+
+    // rodata: read-only, no-execute
+    let rodata = paging::flags_of(VirtAddr::from_ptr(&RODATA)).expect("rodata not mapped");
+    assert!(!rodata.contains(PageTableFlags::WRITABLE));
+    assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+
+    // data/bss: writable, no-execute
+    let data = paging::flags_of(VirtAddr::from_ptr(&raw const DATA)).expect("data not mapped");
+    assert!(data.contains(PageTableFlags::WRITABLE));
+    assert!(data.contains(PageTableFlags::NO_EXECUTE));
+
+    // the HHDM still translates back to the frame it came from
+    let frame = frame::allocate_frame().expect("out of frames");
+    let virt = crate::memory::phys_to_virt(frame.start_address());
+    assert_eq!(paging::translate(virt), Some(frame.start_address()));
+    unsafe { frame::deallocate_frame(frame) };
+}
+
+pub fn test_mmio_mapping() {
+    use crate::memory::{
+        mmio::{self, CacheType},
+        paging, phys_to_virt,
+    };
+    use x86_64::structures::paging::PageTableFlags;
+
+    let frame = frame::allocate_frame().expect("out of frames");
+    let phys = frame.start_address();
+
+    // 1) an unaligned request maps the whole page and keeps the offset
+    let region = unsafe { mmio::map_mmio(phys + 0x10u64, 8, CacheType::WriteBack) }.unwrap();
+    assert_eq!(region.virt_addr().as_u64() % 4096, 0x10);
+    assert_eq!(paging::translate(region.virt_addr()), Some(phys + 0x10u64));
+
+    region.write::<u64>(0, 0xDEAD_BEEF_CAFE_F00D);
+    let through_hhdm = unsafe { *phys_to_virt(phys + 0x10u64).as_ptr::<u64>() };
+    assert_eq!(through_hhdm, 0xDEAD_BEEF_CAFE_F00D);
+
+    let virt = region.virt_addr();
+    unsafe { mmio::unmap_mmio(region) };
+    assert_eq!(paging::translate(virt), None);
+
+    unsafe { frame::deallocate_frame(frame) };
+
+    // 2) the cache type ends up in the page table entry; uncached on a real
+    //    device page, since RAM is already mapped write-back by the HHDM
+    let hpet = crate::acpi::hpet().expect("no HPET to map");
+    let region = unsafe { mmio::map_mmio(hpet.address, 0x400, CacheType::Uncached) }.unwrap();
+    let flags = paging::flags_of(region.virt_addr()).unwrap();
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    let period_fs = region.read::<u64>(0) >> 32;
+    assert_eq!(Some(1_000_000_000_000_000 / period_fs), crate::time::hpet::frequency());
+    unsafe { mmio::unmap_mmio(region) };
+}
+
+pub fn test_stack_guard_pages() {
+    use crate::gdt::{self, KERNEL_STACK_SIZE};
+    use crate::memory::{paging, stack};
+
+    // 1) a fresh stack is mapped up to its top with a hole right below it
+    let s = stack::allocate_stack(16 * 1024).expect("failed to allocate stack");
+    assert_eq!(s.top().as_u64() % 16, 0);
+    assert!(paging::translate(s.top() - 8u64).is_some());
+    assert!(paging::translate(s.bottom()).is_some());
+    assert!(paging::translate(s.bottom() - 1u64).is_none());
+    assert!(s.guard_contains(s.bottom() - 1u64));
+
+    // 2) the per-cpu stacks report which cpu a guard page belongs to
+    let below_bsp_stack = gdt::kernel_stack_top(0) - KERNEL_STACK_SIZE as u64 - 8u64;
+    assert_eq!(gdt::stack_guard_hit(below_bsp_stack), Some((0, "kernel")));
+    assert_eq!(gdt::stack_guard_hit(gdt::kernel_stack_top(0) - 8u64), None);
+}
+
+pub fn test_bootloader_memory_reclaimed() {
+    use crate::boot::boot_info;
+    use limine::memory_map::EntryType;
+
+    // every whole frame of a usable or reclaimed region is now counted as usable
+    let whole_frames = |base: u64, length: u64| {
+        ((base + length) / frame::FRAME_SIZE).saturating_sub(base.div_ceil(frame::FRAME_SIZE))
+    };
+    let expected: u64 = boot_info()
+        .memory_map
+        .iter()
+        .filter(|e| {
+            e.entry_type == EntryType::USABLE || e.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+        })
+        .map(|e| whole_frames(e.base, e.length))
+        .sum();
+
+    // only frame 0 may be held back
+    let usable = frame::stats().usable_frames as u64;
+    assert!(usable + 1 >= expected, "{} usable frames, expected {}", usable, expected);
+}
+
+pub fn test_dma_buffer() {
+    use crate::memory::{dma::DMA32_LIMIT, paging, phys_to_virt, DmaBuffer, DmaConstraints};
+
+    let before = frame::stats();
+
+    // 1) both addresses describe the same, contiguous memory
+    let constraints = DmaConstraints {
+        align: 64 * 1024,
+        below_4gib: true,
+    };
+    let mut buf = DmaBuffer::new(3000, 0u32, constraints).expect("DMA allocation failed");
+    assert_eq!(buf.phys_addr().as_u64() % (64 * 1024), 0);
+    assert!(buf.phys_addr().as_u64() + buf.capacity_bytes() as u64 <= DMA32_LIMIT);
+    assert_eq!(buf.capacity_bytes(), 3 * 4096);
+    assert_eq!(paging::translate(buf.virt_addr()), Some(buf.phys_addr()));
+    assert_eq!(buf.phys_addr_of(1024), Ok(buf.phys_addr() + 4096u64));
+    assert!(buf.phys_addr_of(3000).is_err());
+    assert!(buf.iter().all(|&v| v == 0));
+
+    buf[1024] = 0xC0FFEE;
+    let through_phys = unsafe { *phys_to_virt(buf.phys_addr_of(1024).unwrap()).as_ptr::<u32>() };
+    assert_eq!(through_phys, 0xC0FFEE);
+
+    // 2) dropping the handle gives the frames back
+    assert_eq!(frame::stats().free_frames, before.free_frames - 3);
+    drop(buf);
+    assert_eq!(frame::stats().free_frames, before.free_frames);
+
+    // 3) sizes that overflow are refused before anything is allocated
+    assert!(DmaBuffer::new(usize::MAX / 2, 0u32, DmaConstraints::default()).is_err());
+    assert_eq!(frame::stats().free_frames, before.free_frames);
+}
+
+pub fn test_vmalloc() {
+    use crate::memory::{paging, vmalloc};
+
+    let before = frame::stats();
+
+    // 1) the region is zeroed, mapped page by page and followed by a guard page
+    let region = vmalloc::vmalloc(3 * 4096 + 100).expect("vmalloc failed");
+    assert_eq!(region.len(), 4 * 4096);
+    // page tables created for the window are kept, so only the data frames come back
+    let during = frame::stats();
+    assert!(during.free_frames <= before.free_frames - 4);
+    let words = unsafe { core::slice::from_raw_parts_mut(region.as_mut_ptr::<u64>(), region.len() / 8) };
+    assert!(words.iter().all(|&w| w == 0));
+    words.fill(0x5A5A_5A5A_5A5A_5A5A);
+    let end = region.start() + region.len() as u64;
+    assert!(paging::translate(end - 1u64).is_some());
+    assert!(paging::translate(end).is_none());
+
+    // 2) freeing unmaps it and returns the frames
+    let start = region.start();
+    unsafe { vmalloc::vfree(region) };
+    assert!(paging::translate(start).is_none());
+    assert_eq!(frame::stats().free_frames, during.free_frames + 4);
+}
+
+pub fn test_demand_paging() {
+    use crate::memory::{paging, vmalloc};
+    use x86_64::VirtAddr;
+
+    let region = vmalloc::vmalloc_lazy(8 * 4096).expect("lazy vmalloc failed");
+    let ptr = region.as_mut_ptr::<u64>();
+
+    // 1) nothing is mapped until a page is touched
+    assert!(paging::translate(region.start()).is_none());
+
+    // 2) the first touch maps a zeroed frame, the other pages stay unmapped
+    let third_page = unsafe { ptr.add(2 * 4096 / 8) };
+    assert_eq!(unsafe { third_page.read_volatile() }, 0);
+    unsafe { third_page.write_volatile(0x1234) };
+    assert_eq!(unsafe { third_page.read_volatile() }, 0x1234);
+    assert!(paging::translate(VirtAddr::from_ptr(third_page)).is_some());
+    assert!(paging::translate(region.start()).is_none());
+
+    // 3) freeing releases only what was faulted in
+    let before_free = frame::stats().free_frames;
+    unsafe { vmalloc::vfree(region) };
+    assert_eq!(frame::stats().free_frames, before_free + 1);
+}
+
+pub fn test_tlb_shootdown() {
+    use crate::ipi;
+    use crate::memory::{paging, phys_to_virt, tlb, vmalloc};
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use x86_64::structures::paging::{Page, PageTableFlags};
+
+    let region = vmalloc::vmalloc(4096).expect("vmalloc failed");
+    let ptr = region.as_mut_ptr::<u64>() as usize;
+    unsafe { (ptr as *mut u64).write_volatile(1) };
+
+    // 1) every other core reads the page, caching the translation
+    let sum = AtomicU64::new(0);
+    let readers = AtomicUsize::new(0);
+    let read_all = || {
+        sum.store(0, Ordering::SeqCst);
+        readers.store(0, Ordering::SeqCst);
+        ipi::call_on_others(&|| {
+            sum.fetch_add(unsafe { (ptr as *const u64).read_volatile() }, Ordering::SeqCst);
+            readers.fetch_add(1, Ordering::SeqCst);
+        });
+    };
+    read_all();
+    assert_eq!(sum.load(Ordering::SeqCst), readers.load(Ordering::SeqCst) as u64);
+
+    // 2) point the page at a different frame; after the shootdown nobody sees the old one
+    let page = Page::containing_address(region.start());
+    let old = unsafe { paging::unmap_page(page) }.unwrap();
+    let new = frame::allocate_frame().expect("out of frames");
+    unsafe { phys_to_virt(new.start_address()).as_mut_ptr::<u64>().write_volatile(2) };
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { paging::map_page(page, new, flags) }.unwrap();
+    tlb::shootdown_page(region.start());
+    read_all();
+    assert_eq!(sum.load(Ordering::SeqCst), 2 * readers.load(Ordering::SeqCst) as u64);
+
+    unsafe {
+        vmalloc::vfree(region);
+        frame::deallocate_frame(old);
+    }
+}
@@ -1,5 +1,9 @@
+pub mod acpi;
 pub mod framebuffer;
 pub mod heap;
+pub mod interrupt;
+pub mod memory;
+pub mod time;
 
 pub fn trivial_assertion() {
     assert_eq!(1, 1);
@@ -0,0 +1,207 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::time::{self, hpet, pit};
+
+pub fn test_hpet_and_calibration() {
+    // 1) q35 has an HPET and its main counter runs
+    assert!(hpet::is_present());
+    let hz = hpet::frequency().unwrap();
+    assert!(hz >= 1_000_000);
+    let a = hpet::counter().unwrap();
+    let b = hpet::counter().unwrap();
+    assert!(hpet::elapsed_ticks(a, b) < hz);
+    let one_second = hpet::ticks_to_nanos(hz).unwrap();
+    assert!(one_second.abs_diff(1_000_000_000) < 1_000);
+
+    // 2) calibrating the HPET against itself gives back its own frequency,
+    //    and the PIT fallback agrees within a few percent
+    let measured = time::measure_frequency(|| hpet::counter().unwrap());
+    assert!(measured.abs_diff(hz) < hz / 100);
+    let count = (pit::PIT_FREQUENCY / 100) as u16;
+    let via_pit = pit::measure(count, || hpet::counter().unwrap()) * 100;
+    assert!(via_pit.abs_diff(hz) < hz / 20);
+
+    // 3) the TSC runs faster than the HPET
+    assert!(time::tsc::calibrate() > hz);
+}
+
+pub fn test_hpet_comparator() {
+    use crate::{apic, ioapic};
+    use crate::interrupt::{allocate_vector, irq_count, unregister_irq, IrqController};
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn handler(_vector: u8) {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let vector = allocate_vector(handler, IrqController::Apic).unwrap();
+    let before = irq_count(vector);
+    let ticks = hpet::nanos_to_ticks(1_000_000).unwrap();
+    let mode = hpet::ComparatorMode::OneShot(ticks);
+    let gsi = hpet::arm_comparator(0, mode, vector, apic::get_apic_id()).unwrap();
+    assert!(ioapic::has_gsi(gsi));
+    while irq_count(vector) == before {
+        core::hint::spin_loop();
+    }
+    hpet::disarm_comparator(0).unwrap();
+    unregister_irq(vector).unwrap();
+    assert!(FIRED.load(Ordering::SeqCst) >= 1);
+
+    assert!(hpet::arm_comparator(hpet::comparator_count(), mode, vector, 0).is_err());
+}
+
+pub fn test_monotonic_clock() {
+    use crate::ipi;
+    use crate::time::{Duration, Instant};
+    use core::sync::atomic::AtomicU64;
+
+    // 1) the clock runs and never goes backwards
+    assert!(time::clock_source().is_some());
+    if time::clock_source() == Some(time::ClockSource::Hpet) {
+        assert!(hpet::has_64bit_counter());
+    }
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+
+    // 2) a busy wait lasts as long as the HPET says it does
+    let start = Instant::now();
+    let hpet_start = hpet::counter().unwrap();
+    time::busy_wait(Duration::from_millis(20));
+    let elapsed = start.elapsed();
+    let hpet_ticks = hpet::elapsed_ticks(hpet_start, hpet::counter().unwrap());
+    let hpet_elapsed = hpet::ticks_to_nanos(hpet_ticks).unwrap();
+    assert!(elapsed >= Duration::from_millis(20));
+    assert!((elapsed.as_nanos() as u64).abs_diff(hpet_elapsed) < 1_000_000);
+
+    // 3) every core reads the same clock
+    let before = Instant::now();
+    let latest = AtomicU64::new(0);
+    ipi::call_on_others(&|| {
+        latest.fetch_max(Instant::now().as_nanos(), Ordering::SeqCst);
+    });
+    let after = Instant::now();
+    let remote = latest.load(Ordering::SeqCst);
+    if remote != 0 {
+        assert!(remote >= before.as_nanos() && remote <= after.as_nanos());
+    }
+
+    // 4) arithmetic
+    let t = Instant::BOOT + Duration::from_nanos(1500);
+    assert_eq!(t - Instant::BOOT, Duration::from_nanos(1500));
+    assert_eq!(Instant::BOOT - t, Duration::ZERO);
+    assert!(Instant::BOOT.checked_sub(Duration::from_nanos(1)).is_none());
+}
+
+pub fn test_timers() {
+    use crate::time::timer;
+    use crate::time::{Duration, Instant, Timeout};
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let pending = timer::pending();
+
+    // 1) a one-shot callback fires no earlier than asked
+    let fired = Arc::new(AtomicBool::new(false));
+    let flag = fired.clone();
+    let start = Instant::now();
+    timer::schedule_after(Duration::from_millis(5), move || flag.store(true, Ordering::SeqCst));
+    while !fired.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(5));
+
+    // 2) a cancelled timer never fires and cannot be cancelled twice
+    let fired = Arc::new(AtomicBool::new(false));
+    let flag = fired.clone();
+    let handle = timer::schedule_after(Duration::from_millis(5), move || flag.store(true, Ordering::SeqCst));
+    assert!(timer::cancel(handle));
+    assert!(!timer::cancel(handle));
+    timer::sleep(Duration::from_millis(10));
+    assert!(!fired.load(Ordering::SeqCst));
+
+    // 3) sleep lasts at least as long as asked
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(20));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    // 4) timeouts expire, unless cancelled first
+    let timeout = Timeout::new(Duration::from_millis(5));
+    let mut cancelled = Timeout::new(Duration::from_millis(5));
+    assert!(cancelled.cancel());
+    while !timeout.has_expired() {
+        core::hint::spin_loop();
+    }
+    time::sleep(Duration::from_millis(5));
+    assert!(!cancelled.has_expired());
+    drop(timeout);
+    drop(cancelled);
+
+    // 5) a periodic timer keeps firing until cancelled
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let handle = timer::schedule_periodic(Duration::from_millis(2), move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    time::sleep(Duration::from_millis(20));
+    assert!(timer::cancel(handle));
+    let fired = count.load(Ordering::SeqCst);
+    assert!(fired >= 5);
+    time::sleep(Duration::from_millis(10));
+    assert_eq!(count.load(Ordering::SeqCst), fired);
+
+    assert_eq!(timer::pending(), pending);
+}
+
+pub fn test_rtc_and_wall_clock() {
+    use crate::time::{rtc, DateTime, Duration, Instant, Timeout};
+
+    // 1) calendar arithmetic against known dates
+    let epoch = DateTime::from_unix(Duration::ZERO);
+    assert_eq!((epoch.year, epoch.month, epoch.day, epoch.hour), (1970, 1, 1, 0));
+    let leap_day = DateTime::from_unix(Duration::from_secs(951_782_400));
+    assert_eq!((leap_day.year, leap_day.month, leap_day.day), (2000, 2, 29));
+    let t = DateTime::from_unix(Duration::new(1_700_000_000, 123_000_000));
+    assert_eq!(t.to_unix(), Duration::new(1_700_000_000, 123_000_000));
+    assert_eq!(alloc::format!("{:#}", t), "2023-11-14 22:13:20.123");
+    assert!(!DateTime { month: 2, day: 29, ..epoch }.is_valid());
+    assert_eq!(DateTime { day: 0, ..epoch }.to_unix(), Duration::ZERO);
+
+    // 2) the RTC has a plausible date and the wall clock agrees with it
+    let rtc_now = rtc::read().unwrap();
+    assert!(rtc_now.is_valid() && rtc_now.year >= 2024);
+    let wall = time::unix_time().unwrap();
+    assert!(wall.as_secs().abs_diff(rtc_now.to_unix().as_secs()) <= 2);
+
+    // 3) the wall clock moves with the monotonic clock
+    let start = Instant::now();
+    let before = time::unix_time().unwrap();
+    time::sleep(Duration::from_millis(20));
+    let after = time::unix_time().unwrap();
+    assert!(after - before >= start.elapsed() - Duration::from_micros(100));
+
+    // 4) the periodic interrupt runs at the asked rate, give or take
+    assert!(rtc::set_periodic(Some(3)).is_err());
+    let ticks = rtc::periodic_ticks();
+    rtc::set_periodic(Some(1024)).unwrap();
+    time::sleep(Duration::from_millis(50));
+    rtc::set_periodic(None).unwrap();
+    let taken = rtc::periodic_ticks() - ticks;
+    assert!((25..=75).contains(&taken));
+
+    // 5) an update interrupt arrives within a second, and has synced the
+    //    wall clock by then
+    let updates = rtc::updates();
+    rtc::set_update_interrupt(true).unwrap();
+    let timeout = Timeout::new(Duration::from_millis(1500));
+    while rtc::updates() == updates {
+        assert!(!timeout.has_expired());
+        core::hint::spin_loop();
+    }
+    rtc::set_update_interrupt(false).unwrap();
+    drop(timeout);
+    assert!(rtc::is_synced());
+}
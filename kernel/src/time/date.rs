@@ -0,0 +1,107 @@
+use core::fmt;
+use core::time::Duration;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar.
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+
+/// A calendar date and time of day, in whatever zone the RTC keeps
+/// (UTC on any sanely configured machine).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// The date `since_epoch` after 1970-01-01 00:00:00.
+    pub fn from_unix(since_epoch: Duration) -> Self {
+        let seconds = since_epoch.as_secs();
+        let (days, time) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+
+        // civil_from_days, counting eras of 400 years from 0000-03-01
+        let days = days + UNIX_EPOCH_DAYS;
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days % DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+        let month = if march_month < 10 { march_month + 3 } else { march_month - 9 };
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            nanosecond: since_epoch.subsec_nanos(),
+        }
+    }
+
+    /// Time since 1970-01-01 00:00:00; zero for earlier dates and for values
+    /// that fail [`is_valid`](Self::is_valid).
+    pub fn to_unix(&self) -> Duration {
+        if self.year < 1970 || !self.is_valid() {
+            return Duration::ZERO;
+        }
+        // days_from_civil, with years starting in March so leap days come last
+        let year = self.year as u64 - (self.month <= 2) as u64;
+        let (era, year_of_era) = (year / 400, year % 400);
+        let march_month = (self.month as u64 + 9) % 12;
+        let day_of_year = (153 * march_month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+
+        let seconds = days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        Duration::new(seconds, self.nanosecond)
+    }
+
+    /// Every field is in range, the day included.
+    pub fn is_valid(&self) -> bool {
+        let leap = self.year.is_multiple_of(4)
+            && (!self.year.is_multiple_of(100) || self.year.is_multiple_of(400));
+        let days_in_month = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return false,
+        };
+        (1..=days_in_month).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && self.nanosecond < 1_000_000_000
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS`; the alternate form (`{:#}`) adds milliseconds.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )?;
+        if f.alternate() {
+            write!(f, ".{:03}", self.nanosecond / 1_000_000)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::acpi::{self, Polarity, TriggerMode};
+use crate::ioapic;
+use crate::memory::mmio::{map_mmio, CacheType, MmioRegion};
+use crate::serial_log;
+
+const HPET_MMIO_SIZE: usize = 0x400;
+
+// general registers
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+// general capabilities
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+
+// general configuration
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// per comparator registers, 0x20 apart
+const fn timer_config(index: u8) -> usize {
+    0x100 + 0x20 * index as usize
+}
+const fn timer_comparator(index: u8) -> usize {
+    0x108 + 0x20 * index as usize
+}
+
+// comparator configuration and capabilities
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// How a comparator fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparatorMode {
+    /// Once, this many main counter ticks from now.
+    OneShot(u64),
+    /// Every this many main counter ticks, starting one period from now.
+    Periodic(u64),
+}
+
+struct Hpet {
+    mmio: MmioRegion,
+    /// Main counter period in femtoseconds.
+    period_fs: u64,
+    comparators: u8,
+    counter_64bit: bool,
+    /// Serializes comparator reprogramming.
+    lock: Mutex<()>,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        self.mmio.read(reg)
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        self.mmio.write(reg, value);
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+fn hpet() -> Result<&'static Hpet, &'static str> {
+    HPET.get().ok_or("no HPET")
+}
+
+/// Map the HPET the ACPI tables describe, stop all its comparators and start
+/// the main counter. Does nothing if there is no HPET.
+pub fn init() {
+    let Some(info) = acpi::hpet() else {
+        serial_log!("HPET: not present");
+        return;
+    };
+    let mmio = match unsafe { map_mmio(info.address, HPET_MMIO_SIZE, CacheType::Uncached) } {
+        Ok(mmio) => mmio,
+        Err(e) => {
+            serial_log!("HPET: {}", e);
+            return;
+        }
+    };
+
+    let caps: u64 = mmio.read(GENERAL_CAPABILITIES);
+    let period_fs = caps >> 32;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        serial_log!("HPET: invalid counter period {} fs", period_fs);
+        return;
+    }
+    let hpet = Hpet {
+        mmio,
+        period_fs,
+        comparators: ((caps >> 8) & 0x1F) as u8 + 1,
+        counter_64bit: caps & CAP_COUNT_SIZE_64 != 0,
+        lock: Mutex::new(()),
+    };
+
+    // halt, quiet every comparator, then count from zero
+    let config = hpet.read(GENERAL_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    hpet.write(GENERAL_CONFIG, config);
+    for index in 0..hpet.comparators {
+        let timer = hpet.read(timer_config(index));
+        hpet.write(timer_config(index), timer & !(TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE));
+    }
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(GENERAL_CONFIG, config | CONFIG_ENABLE);
+
+    serial_log!(
+        "HPET: {} Hz, {} comparators, {}-bit counter",
+        FEMTOS_PER_SECOND / period_fs,
+        hpet.comparators,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+    HPET.call_once(|| hpet);
+}
+
+pub fn is_present() -> bool {
+    HPET.get().is_some()
+}
+
+/// Main counter ticks per second.
+pub fn frequency() -> Option<u64> {
+    HPET.get().map(|hpet| FEMTOS_PER_SECOND / hpet.period_fs)
+}
+
+/// Whether the main counter is 64 bits wide rather than wrapping after a few
+/// minutes at 32.
+pub fn has_64bit_counter() -> bool {
+    HPET.get().is_some_and(|hpet| hpet.counter_64bit)
+}
+
+/// The free running main counter. Monotonic; a 32-bit counter wraps after
+/// a few minutes, which [`elapsed_ticks`] accounts for over short spans.
+pub fn counter() -> Option<u64> {
+    HPET.get().map(|hpet| hpet.read(MAIN_COUNTER))
+}
+
+/// Ticks from `start` to `end`, allowing for one wrap of a 32-bit counter.
+pub fn elapsed_ticks(start: u64, end: u64) -> u64 {
+    match HPET.get() {
+        Some(hpet) if !hpet.counter_64bit => (end as u32).wrapping_sub(start as u32) as u64,
+        _ => end.wrapping_sub(start),
+    }
+}
+
+/// Convert main counter ticks to nanoseconds.
+pub fn ticks_to_nanos(ticks: u64) -> Option<u64> {
+    HPET.get().map(|hpet| (ticks as u128 * hpet.period_fs as u128 / 1_000_000) as u64)
+}
+
+/// Convert nanoseconds to main counter ticks, rounding up.
+pub fn nanos_to_ticks(nanos: u64) -> Option<u64> {
+    HPET.get().map(|hpet| (nanos as u128 * 1_000_000).div_ceil(hpet.period_fs as u128) as u64)
+}
+
+pub fn comparator_count() -> u8 {
+    HPET.get().map_or(0, |hpet| hpet.comparators)
+}
+
+/// Arm comparator `index` to raise `vector` on the CPU with local APIC ID
+/// `apic_id`, routed through the I/O APIC. Returns the GSI it was wired to.
+pub fn arm_comparator(
+    index: u8,
+    mode: ComparatorMode,
+    vector: u8,
+    apic_id: u32,
+) -> Result<u32, &'static str> {
+    let hpet = hpet()?;
+    if index >= hpet.comparators {
+        return Err("no such HPET comparator");
+    }
+
+    interrupts::without_interrupts(|| {
+        let _guard = hpet.lock.lock();
+        let config = hpet.read(timer_config(index));
+        if matches!(mode, ComparatorMode::Periodic(_)) && config & TIMER_PERIODIC_CAPABLE == 0 {
+            return Err("HPET comparator cannot run periodically");
+        }
+
+        // prefer the PCI range over GSIs an ISA device may be wired to, and
+        // skip routes past the pins the I/O APICs actually have
+        let routes = (config >> 32) as u32;
+        let gsi = (16..32)
+            .chain(0..16)
+            .find(|&gsi| routes & (1 << gsi) != 0 && ioapic::has_gsi(gsi))
+            .ok_or("HPET comparator has no I/O APIC route")?;
+        ioapic::route_gsi(gsi, vector, apic_id, Polarity::ActiveHigh, TriggerMode::Edge)?;
+
+        let mut config = (config & !(TIMER_ROUTE_MASK | TIMER_PERIODIC | TIMER_FSB_ENABLE))
+            | ((gsi as u64) << TIMER_ROUTE_SHIFT)
+            | TIMER_INT_ENABLE;
+        let now = hpet.read(MAIN_COUNTER);
+        match mode {
+            ComparatorMode::OneShot(ticks) => {
+                hpet.write(timer_config(index), config);
+                hpet.write(timer_comparator(index), now.wrapping_add(ticks));
+            }
+            ComparatorMode::Periodic(period) => {
+                // first write sets the first deadline, the second the period
+                config |= TIMER_PERIODIC | TIMER_VALUE_SET;
+                hpet.write(timer_config(index), config);
+                hpet.write(timer_comparator(index), now.wrapping_add(period));
+                hpet.write(timer_comparator(index), period);
+            }
+        }
+        Ok(gsi)
+    })
+}
+
+/// Stop comparator `index` from raising interrupts and mask the GSI it was
+/// routed to.
+pub fn disarm_comparator(index: u8) -> Result<(), &'static str> {
+    let hpet = hpet()?;
+    if index >= hpet.comparators {
+        return Err("no such HPET comparator");
+    }
+    interrupts::without_interrupts(|| {
+        let _guard = hpet.lock.lock();
+        let config = hpet.read(timer_config(index));
+        hpet.write(timer_config(index), config & !(TIMER_INT_ENABLE | TIMER_PERIODIC));
+        if config & TIMER_INT_ENABLE != 0 {
+            let gsi = ((config & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT) as u32;
+            ioapic::set_gsi_masked(gsi, true)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,152 @@
+mod date;
+pub mod hpet;
+mod instant;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use crate::serial_log;
+
+pub use core::time::Duration;
+pub use date::DateTime;
+pub use instant::Instant;
+pub use timer::{sleep, Timeout};
+
+/// Length of the window counters are calibrated over.
+const CALIBRATION_MS: u64 = 10;
+
+/// Counter the monotonic clock is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// Invariant TSC: cheapest to read, same rate on every core.
+    Tsc,
+    /// HPET main counter, for CPUs whose TSC rate changes with power states.
+    /// Only used when the counter is 64 bits wide.
+    Hpet,
+}
+
+struct Clock {
+    source: ClockSource,
+    /// Counter value at `Instant::BOOT`.
+    base: u64,
+    /// Nanoseconds per counter tick, as a 32.32 fixed point number.
+    scale: u64,
+}
+
+impl Clock {
+    fn new(source: ClockSource, hz: u64) -> Self {
+        let scale = (1_000_000_000u128 << 32).div_ceil(hz as u128) as u64;
+        let mut clock = Self { source, base: 0, scale };
+        clock.base = clock.read();
+        clock
+    }
+
+    fn read(&self) -> u64 {
+        match self.source {
+            ClockSource::Tsc => tsc::read(),
+            ClockSource::Hpet => hpet::counter().unwrap_or(0),
+        }
+    }
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+/// Nanoseconds from the Unix epoch to `Instant::BOOT`, 0 until the RTC has
+/// been read.
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Bring up the platform clocks and start the monotonic clock. Runs once on
+/// the BSP, after ACPI.
+pub fn init() {
+    hpet::init();
+    tsc::init();
+
+    // a 32-bit HPET wraps every few minutes, which the clock can't follow
+    // without being read at least that often; a drifting TSC is the lesser evil
+    let (source, hz) = match hpet::frequency() {
+        Some(hz) if !tsc::is_invariant() && hpet::has_64bit_counter() => (ClockSource::Hpet, hz),
+        _ => (ClockSource::Tsc, tsc::frequency()),
+    };
+    serial_log!("clock: {:?} at {} Hz (TSC {} Hz)", source, hz, tsc::frequency());
+    CLOCK.call_once(|| Clock::new(source, hz));
+
+    rtc::init();
+}
+
+/// Which counter [`Instant::now`] reads, once the clock is running.
+pub fn clock_source() -> Option<ClockSource> {
+    CLOCK.get().map(|clock| clock.source)
+}
+
+/// Nanoseconds since the clock started; 0 before [`init`].
+fn nanos_since_boot() -> u64 {
+    let Some(clock) = CLOCK.get() else {
+        return 0;
+    };
+    let ticks = clock.read().wrapping_sub(clock.base);
+    ((ticks as u128 * clock.scale as u128) >> 32) as u64
+}
+
+/// Time since the clock started.
+pub fn uptime() -> Duration {
+    Instant::BOOT.elapsed()
+}
+
+/// Line the wall clock up so that it read `time` at `at`.
+fn set_wall_clock(time: DateTime, at: Instant) {
+    let nanos = time.to_unix().as_nanos() as u64;
+    BOOT_UNIX_NANOS.store(nanos.saturating_sub(at.as_nanos()).max(1), Ordering::Relaxed);
+}
+
+/// Time since the Unix epoch, if the RTC has been read.
+pub fn unix_time() -> Option<Duration> {
+    match BOOT_UNIX_NANOS.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(Duration::from_nanos(boot + Instant::now().as_nanos())),
+    }
+}
+
+/// The current date and time: the RTC's reading at boot carried forward by
+/// the monotonic clock.
+pub fn wall_clock_now() -> Option<DateTime> {
+    unix_time().map(DateTime::from_unix)
+}
+
+/// Spin until `duration` has passed.
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Rate in Hz of a counter sampled by `read`, measured against the HPET or,
+/// if there is none, PIT channel 2. Interrupts stay off for the ~10 ms
+/// window so nothing stretches the interval between two samples.
+pub fn measure_frequency(mut read: impl FnMut() -> u64) -> u64 {
+    interrupts::without_interrupts(|| match (hpet::frequency(), hpet::counter()) {
+        (Some(hz), Some(start)) => {
+            let window = hz * CALIBRATION_MS / 1000;
+            let first = read();
+            let mut now = start;
+            while hpet::elapsed_ticks(start, now) < window {
+                core::hint::spin_loop();
+                now = hpet::counter().unwrap();
+            }
+            let last = read();
+            let elapsed = hpet::elapsed_ticks(start, now);
+            (last.wrapping_sub(first) as u128 * hz as u128 / elapsed as u128) as u64
+        }
+        _ => {
+            let count = pit::PIT_FREQUENCY * CALIBRATION_MS / 1000;
+            let delta = pit::measure(count as u16, read);
+            (delta as u128 * pit::PIT_FREQUENCY as u128 / count as u128) as u64
+        }
+    })
+}
@@ -0,0 +1,51 @@
+use x86_64::instructions::port::Port;
+
+/// Input clock of the 8253/8254 PIT.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B: channel 2 gate, speaker and OUT2 status.
+const PORT_B: u16 = 0x61;
+
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count),
+/// binary.
+const CHANNEL_2_ONE_SHOT: u8 = 0xB0;
+
+/// Count `count` PIT ticks on channel 2 with the speaker disconnected and
+/// return how far `read` advanced in that time.
+///
+/// Channel 2 is the one channel whose gate software controls and whose output
+/// can be polled, so unlike channel 0 it gives a real, interrupt free
+/// interval. `count` ticks last `count / PIT_FREQUENCY` seconds, at most
+/// about 55 ms.
+pub fn measure(count: u16, mut read: impl FnMut() -> u64) -> u64 {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+
+    unsafe {
+        // gate low holds the counter while it is loaded
+        let saved = port_b.read();
+        port_b.write(saved & !(PORT_B_GATE_2 | PORT_B_SPEAKER));
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // raising the gate starts the count; OUT2 goes high when it runs out
+        port_b.write((saved & !PORT_B_SPEAKER) | PORT_B_GATE_2);
+        let start = read();
+        while port_b.read() & PORT_B_OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read();
+
+        port_b.write(saved);
+        end.wrapping_sub(start)
+    }
+}
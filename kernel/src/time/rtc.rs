@@ -0,0 +1,317 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::{set_wall_clock, DateTime, Duration, Instant};
+use crate::acpi;
+use crate::serial_log;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// time and date registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY_OF_MONTH: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+
+// status registers
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+// status A: update in progress, periodic rate select
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_RATE_MASK: u8 = 0x0F;
+
+// status B: data format and interrupt enables
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_UPDATE_INT: u8 = 1 << 4;
+const B_PERIODIC_INT: u8 = 1 << 6;
+
+// status C: which interrupt fired; reading it acknowledges
+const C_UPDATE: u8 = 1 << 4;
+const C_PERIODIC: u8 = 1 << 6;
+
+/// 12 hour mode keeps PM in the top bit of the hour.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Rate select `r` makes the periodic interrupt fire at `32768 >> (r - 1)` Hz.
+const BASE_FREQUENCY: u32 = 32_768;
+/// Rates 1 and 2 are reserved on most chipsets, leaving 3 (8192 Hz) to
+/// 15 (2 Hz).
+const MAX_PERIODIC_HZ: u32 = 8192;
+const MIN_PERIODIC_HZ: u32 = 2;
+
+/// An update cycle holds UIP for under 2 ms; longer means there is no RTC.
+const UPDATE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Year the two digit year counts from when there is no century register.
+const DEFAULT_CENTURY: u16 = 2000;
+
+/// Serializes index/data pairs on the CMOS ports.
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// CMOS index of the century register, 0 if the FADT names none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+
+/// The wall clock was set on an update interrupt, to well under a second.
+static SYNCED: AtomicBool = AtomicBool::new(false);
+/// Someone other than the wall clock sync wants update interrupts.
+static UPDATE_WANTED: AtomicBool = AtomicBool::new(false);
+
+/// Read CMOS register `reg`. NMIs stay enabled.
+///
+/// # Safety
+/// The caller must hold `CMOS_LOCK` with interrupts disabled.
+unsafe fn cmos_read(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// # Safety
+/// As for [`cmos_read`].
+unsafe fn cmos_write(reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let _guard = CMOS_LOCK.lock();
+        f()
+    })
+}
+
+/// The time registers as stored, before BCD and 12 hour decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// # Safety
+/// As for [`cmos_read`].
+unsafe fn read_raw() -> RawTime {
+    let century = CENTURY_REGISTER.load(Ordering::Relaxed);
+    unsafe {
+        RawTime {
+            second: cmos_read(SECONDS),
+            minute: cmos_read(MINUTES),
+            hour: cmos_read(HOURS),
+            day: cmos_read(DAY_OF_MONTH),
+            month: cmos_read(MONTH),
+            year: cmos_read(YEAR),
+            century: if century != 0 { cmos_read(century) } else { 0 },
+        }
+    }
+}
+
+/// # Safety
+/// As for [`cmos_read`].
+unsafe fn update_in_progress() -> bool {
+    let status_a = unsafe { cmos_read(STATUS_A) };
+    status_a & A_UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & B_BINARY != 0;
+    let number = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    // 12 AM is hour 0 and 12 PM hour 12
+    let mut hour = number(raw.hour & !HOUR_PM);
+    if status_b & B_24_HOUR == 0 {
+        hour %= 12;
+        if raw.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let year = number(raw.year) as u16;
+    let year = match raw.century {
+        0 => DEFAULT_CENTURY + year,
+        century => number(century) as u16 * 100 + year,
+    };
+    DateTime {
+        year,
+        month: number(raw.month),
+        day: number(raw.day),
+        hour,
+        minute: number(raw.minute),
+        second: number(raw.second),
+        nanosecond: 0,
+    }
+}
+
+/// Read the date and time straight from the RTC, whole seconds only.
+///
+/// The registers change mid-read during an update cycle, so this waits for
+/// any update to finish and reads until two reads in a row agree. Returns
+/// `None` if there is no working RTC.
+pub fn read() -> Option<DateTime> {
+    let deadline = Instant::now() + UPDATE_TIMEOUT;
+    let wait_for_update = || {
+        while with_cmos(|| unsafe { update_in_progress() }) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    };
+
+    let mut last = None;
+    loop {
+        if !wait_for_update() {
+            return None;
+        }
+        let (raw, status_b) = with_cmos(|| unsafe { (read_raw(), cmos_read(STATUS_B)) });
+        if last == Some(raw) {
+            let time = decode(raw, status_b);
+            return time.is_valid().then_some(time);
+        }
+        last = Some(raw);
+    }
+}
+
+/// Set the wall clock from the RTC. Runs once on the BSP, once the monotonic
+/// clock is running; [`init_interrupts`] later refines the sub-second part.
+pub fn init() {
+    let has_rtc = acpi::fadt().is_none_or(|fadt| fadt.has_cmos_rtc());
+    if !has_rtc {
+        serial_log!("RTC: not present");
+        return;
+    }
+    if let Some(fadt) = acpi::fadt() {
+        CENTURY_REGISTER.store(fadt.century_register, Ordering::Relaxed);
+    }
+    PRESENT.store(true, Ordering::Relaxed);
+
+    match read() {
+        Some(now) => {
+            set_wall_clock(now, Instant::now());
+            serial_log!("RTC: {}", now);
+        }
+        None => serial_log!("RTC: no valid time"),
+    }
+}
+
+/// Rewrite the interrupt enables in status B, then acknowledge whatever is
+/// latched so the IRQ line drops and the next event raises a fresh edge.
+fn update_interrupt_enables(f: impl FnOnce(u8) -> u8) {
+    with_cmos(|| unsafe {
+        let status_b = cmos_read(STATUS_B);
+        cmos_write(STATUS_B, f(status_b));
+        cmos_read(STATUS_C);
+    });
+}
+
+fn sync_update_interrupt() {
+    let enable = UPDATE_WANTED.load(Ordering::Relaxed) || !SYNCED.load(Ordering::Relaxed);
+    update_interrupt_enables(|b| if enable { b | B_UPDATE_INT } else { b & !B_UPDATE_INT });
+}
+
+/// Start the update interrupt, which sets the wall clock to the moment the
+/// RTC's seconds tick over. Runs once on the BSP, after the RTC's ISA IRQ is
+/// routed.
+pub fn init_interrupts() {
+    if is_present() {
+        sync_update_interrupt();
+    }
+}
+
+/// Turn the periodic interrupt on at `hz`, a power of two from 2 to 8192,
+/// or off with `None`.
+pub fn set_periodic(hz: Option<u32>) -> Result<(), &'static str> {
+    if !is_present() {
+        return Err("no RTC");
+    }
+    let Some(hz) = hz else {
+        update_interrupt_enables(|b| b & !B_PERIODIC_INT);
+        return Ok(());
+    };
+    if !hz.is_power_of_two() || !(MIN_PERIODIC_HZ..=MAX_PERIODIC_HZ).contains(&hz) {
+        return Err("RTC periodic rate must be a power of two from 2 to 8192 Hz");
+    }
+    let rate = (BASE_FREQUENCY / hz).trailing_zeros() as u8 + 1;
+    with_cmos(|| unsafe {
+        let status_a = cmos_read(STATUS_A);
+        cmos_write(STATUS_A, (status_a & !A_RATE_MASK) | rate);
+    });
+    update_interrupt_enables(|b| b | B_PERIODIC_INT);
+    Ok(())
+}
+
+/// Keep the once-a-second update interrupt running, or stop it once the
+/// wall clock no longer needs it.
+pub fn set_update_interrupt(enabled: bool) -> Result<(), &'static str> {
+    if !is_present() {
+        return Err("no RTC");
+    }
+    UPDATE_WANTED.store(enabled, Ordering::Relaxed);
+    sync_update_interrupt();
+    Ok(())
+}
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Periodic interrupts taken since boot.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Update interrupts taken since boot, one per RTC second while enabled.
+pub fn updates() -> u64 {
+    UPDATES.load(Ordering::Relaxed)
+}
+
+/// The wall clock has been lined up with an RTC update.
+pub fn is_synced() -> bool {
+    SYNCED.load(Ordering::Relaxed)
+}
+
+/// Handler for the RTC's ISA IRQ.
+pub fn handle_interrupt(_vector: u8) {
+    let flags = with_cmos(|| unsafe { cmos_read(STATUS_C) });
+    if flags & C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & C_UPDATE != 0 {
+        UPDATES.fetch_add(1, Ordering::Relaxed);
+        if !SYNCED.load(Ordering::Relaxed) {
+            // the registers just changed and hold still for almost a second
+            let at = Instant::now();
+            let (raw, status_b) = with_cmos(|| unsafe { (read_raw(), cmos_read(STATUS_B)) });
+            let now = decode(raw, status_b);
+            if now.is_valid() {
+                set_wall_clock(now, at);
+                SYNCED.store(true, Ordering::Relaxed);
+                sync_update_interrupt();
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use super::{busy_wait, Duration, Instant};
+use crate::apic::{self, APIC_TIMER_VECTOR};
+use crate::boot::boot_info;
+use crate::interrupt::{register_irq, IrqController};
+use crate::percpu::this_cpu;
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Periodic(Box<dyn FnMut() + Send>),
+}
+
+struct Timer {
+    deadline: u64,
+    period: Option<Duration>,
+    /// Taken out while a periodic callback runs, so it runs unlocked.
+    callback: Option<Callback>,
+}
+
+/// One core's pending timers. `order` holds `(deadline, id)` of every timer
+/// waiting to fire, earliest first.
+struct TimerQueue {
+    timers: BTreeMap<u64, Timer>,
+    order: BTreeSet<(u64, u64)>,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self { timers: BTreeMap::new(), order: BTreeSet::new() }
+    }
+
+    /// Program the local APIC timer for the earliest deadline, if any.
+    fn rearm(&self) {
+        match self.order.first() {
+            Some(&(deadline, _)) => apic::arm_timer(Instant::BOOT + Duration::from_nanos(deadline)),
+            None => apic::disarm_timer(),
+        }
+    }
+}
+
+/// Indexed like `BootInfo::cpus`.
+static QUEUES: Once<Vec<Mutex<TimerQueue>>> = Once::new();
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A scheduled callback; pass it to [`cancel`] to stop it. Dropping the
+/// handle leaves the timer running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    cpu: usize,
+    id: u64,
+}
+
+/// Set up a timer queue per CPU and take over the local APIC timer vector.
+/// Runs once on the BSP, before any AP is started.
+pub fn init() {
+    QUEUES.call_once(|| boot_info().cpus.iter().map(|_| Mutex::new(TimerQueue::new())).collect());
+    register_irq(APIC_TIMER_VECTOR, timer_interrupt, IrqController::Apic).expect("timer vector taken");
+}
+
+fn queues() -> &'static [Mutex<TimerQueue>] {
+    QUEUES.get().expect("timers not initialized")
+}
+
+fn current_cpu() -> usize {
+    this_cpu().index()
+}
+
+fn insert(deadline: Instant, period: Option<Duration>, callback: Callback) -> TimerHandle {
+    let cpu = current_cpu();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let deadline = deadline.as_nanos();
+
+    interrupts::without_interrupts(|| {
+        let mut queue = queues()[cpu].lock();
+        queue.timers.insert(id, Timer { deadline, period, callback: Some(callback) });
+        queue.order.insert((deadline, id));
+        if queue.order.first() == Some(&(deadline, id)) {
+            queue.rearm();
+        }
+    });
+    TimerHandle { cpu, id }
+}
+
+/// Run `f` in interrupt context on the calling CPU once `deadline` has
+/// passed.
+pub fn schedule_at(deadline: Instant, f: impl FnOnce() + Send + 'static) -> TimerHandle {
+    insert(deadline, None, Callback::Once(Box::new(f)))
+}
+
+/// Run `f` in interrupt context on the calling CPU after `delay`.
+pub fn schedule_after(delay: Duration, f: impl FnOnce() + Send + 'static) -> TimerHandle {
+    schedule_at(Instant::now() + delay, f)
+}
+
+/// Run `f` in interrupt context on the calling CPU every `period`, until
+/// cancelled. Periods missed while interrupts were off are skipped, not
+/// made up for.
+pub fn schedule_periodic(period: Duration, f: impl FnMut() + Send + 'static) -> TimerHandle {
+    assert!(!period.is_zero(), "periodic timer needs a period");
+    insert(Instant::now() + period, Some(period), Callback::Periodic(Box::new(f)))
+}
+
+/// Stop a timer. Returns whether it was still pending; a one-shot timer
+/// that already fired (or is firing right now) cannot be cancelled.
+pub fn cancel(handle: TimerHandle) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut queue = queues()[handle.cpu].lock();
+        let Some(timer) = queue.timers.remove(&handle.id) else {
+            return false;
+        };
+        queue.order.remove(&(timer.deadline, handle.id));
+        if handle.cpu == current_cpu() {
+            queue.rearm();
+        }
+        true
+    })
+}
+
+/// Take the earliest timer off `cpu`'s queue if it is due.
+fn pop_expired(cpu: usize, now: u64) -> Option<(u64, Callback)> {
+    let mut queue = queues()[cpu].lock();
+    let &(deadline, id) = queue.order.first().filter(|&&(deadline, _)| deadline <= now)?;
+    queue.order.remove(&(deadline, id));
+    let timer = queue.timers.get_mut(&id).expect("queued timer without an entry");
+    match timer.period {
+        Some(_) => timer.callback.take().map(|callback| (id, callback)),
+        None => queue.timers.remove(&id).and_then(|timer| timer.callback).map(|callback| (id, callback)),
+    }
+}
+
+fn timer_interrupt(_vector: u8) {
+    let Some(queues) = QUEUES.get() else {
+        return;
+    };
+    let cpu = current_cpu();
+
+    // callbacks run without the queue locked so they can schedule and cancel
+    while let Some((id, callback)) = pop_expired(cpu, Instant::now().as_nanos()) {
+        this_cpu().stats.timers_fired.fetch_add(1, Ordering::Relaxed);
+        match callback {
+            Callback::Once(f) => f(),
+            Callback::Periodic(mut f) => {
+                f();
+                let mut queue = queues[cpu].lock();
+                // gone if the callback (or another CPU) cancelled it meanwhile
+                if let Some(timer) = queue.timers.get_mut(&id) {
+                    let period = timer.period.unwrap().as_nanos() as u64;
+                    let now = Instant::now().as_nanos();
+                    let mut next = timer.deadline + period;
+                    if next <= now {
+                        next = now + period;
+                    }
+                    timer.deadline = next;
+                    timer.callback = Some(Callback::Periodic(f));
+                    queue.order.insert((next, id));
+                }
+            }
+        }
+    }
+    queues[cpu].lock().rearm();
+}
+
+/// Put the calling CPU to sleep for at least `duration`. With interrupts
+/// off there is nothing to wake it, so it spins instead.
+pub fn sleep(duration: Duration) {
+    if !interrupts::are_enabled() || QUEUES.get().is_none() {
+        busy_wait(duration);
+        return;
+    }
+    let deadline = Instant::now() + duration;
+    let woken = Arc::new(AtomicBool::new(false));
+    let flag = woken.clone();
+    schedule_at(deadline, move || flag.store(true, Ordering::Release));
+
+    // other interrupts wake us too; only the timer's flag ends the sleep
+    while !woken.load(Ordering::Acquire) {
+        interrupts::disable();
+        if woken.load(Ordering::Acquire) {
+            interrupts::enable();
+            break;
+        }
+        // sti; hlt without a gap, so the wakeup cannot slip in between
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// A deadline that can be polled and cancelled, for waits that should give
+/// up eventually.
+#[derive(Debug)]
+pub struct Timeout {
+    expired: Arc<AtomicBool>,
+    handle: Option<TimerHandle>,
+}
+
+impl Timeout {
+    /// Start a timeout that expires `duration` from now.
+    pub fn new(duration: Duration) -> Self {
+        let expired = Arc::new(AtomicBool::new(false));
+        let flag = expired.clone();
+        let handle = schedule_after(duration, move || flag.store(true, Ordering::Release));
+        Self { expired, handle: Some(handle) }
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.expired.load(Ordering::Acquire)
+    }
+
+    /// Stop the timeout; it never expires afterwards. Returns whether it
+    /// was cancelled before expiring.
+    pub fn cancel(&mut self) -> bool {
+        self.handle.take().is_some_and(cancel)
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Timers waiting on the calling CPU.
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| queues()[current_cpu()].lock().order.len())
+}
+
@@ -0,0 +1,81 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+
+use super::measure_frequency;
+
+/// Offset added to the TSC on reads; writing it shifts one core's counter.
+const IA32_TSC_ADJUST: u32 = 0x3B;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+const CPUID_EBX_TSC_ADJUST: u32 = 1 << 1;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The BSP's IA32_TSC_ADJUST, copied to every AP so all counters agree.
+static BSP_ADJUST: AtomicU64 = AtomicU64::new(0);
+static HAS_ADJUST: AtomicBool = AtomicBool::new(false);
+
+/// This core's time stamp counter.
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// The TSC ticks at a constant rate in every P-, C- and T-state, so it can
+/// serve as a clock.
+pub fn is_invariant() -> bool {
+    let max = unsafe { __cpuid(CPUID_EXTENDED_MAX) }.eax;
+    max >= CPUID_POWER_MANAGEMENT
+        && unsafe { __cpuid(CPUID_POWER_MANAGEMENT) }.edx & CPUID_EDX_INVARIANT_TSC != 0
+}
+
+fn has_adjust() -> bool {
+    unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & CPUID_EBX_TSC_ADJUST != 0
+}
+
+/// TSC rate the CPU reports itself (leaf 0x15 crystal clock and ratio), if
+/// it reports all of it.
+fn cpuid_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0) }.eax < 0x15 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(0x15) };
+    let (denominator, numerator, crystal_hz) = (leaf.eax as u64, leaf.ebx as u64, leaf.ecx as u64);
+    (denominator != 0 && numerator != 0 && crystal_hz != 0)
+        .then(|| crystal_hz * numerator / denominator)
+}
+
+/// TSC ticks per second, measured on the calling core.
+pub fn calibrate() -> u64 {
+    measure_frequency(read)
+}
+
+/// Find the TSC rate and remember the BSP's TSC adjustment. Runs once on the
+/// BSP, after the HPET is up.
+pub fn init() {
+    let hz = cpuid_frequency().unwrap_or_else(calibrate);
+    FREQUENCY.store(hz, Ordering::Relaxed);
+
+    if has_adjust() {
+        let adjust = unsafe { Msr::new(IA32_TSC_ADJUST).read() };
+        BSP_ADJUST.store(adjust, Ordering::Relaxed);
+        HAS_ADJUST.store(true, Ordering::Release);
+    }
+}
+
+/// Line this AP's TSC up with the BSP's. Firmware may leave the cores with
+/// different adjustments, which would make time jump between them.
+pub fn init_ap() {
+    if HAS_ADJUST.load(Ordering::Acquire) {
+        unsafe { Msr::new(IA32_TSC_ADJUST).write(BSP_ADJUST.load(Ordering::Relaxed)) };
+    }
+}
+
+/// TSC ticks per second, 0 before [`init`].
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}